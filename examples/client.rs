@@ -31,6 +31,15 @@ fn main() {
     let connection = endpoint.connect(&address).unwrap();
     let connection_copy = connection.clone();
     println!("Created Arc copies.");
+    println!("Waiting for key exchange...");
+    while *connection.crypt_state.read().unwrap() != CryptState::Symm {
+        if *connection.state.read().unwrap() == ConnectionState::Disconnected {
+            println!("ERROR! Connection failed! Terminating program.");
+            std::process::exit(-1);
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    println!("Connection is secure.");
     let join_handle = std::thread::spawn(move || {
         receive_loop(endpoint_copy, connection_copy);
     });
@@ -88,7 +88,8 @@ fn main() {
                 package.data.resize(data.len(), 0);
                 package.data.clone_from_slice(data);
 
-                endpoint.send(package).unwrap();
+                // Connections still in the handshake can not receive data yet
+                endpoint.send(package).unwrap_or(0);
             }
         }
 
@@ -0,0 +1,155 @@
+use std::fs;
+use std::net::IpAddr;
+
+use openssl::x509::*;
+use openssl::x509::store::*;
+use openssl::stack::Stack;
+use openssl::nid::Nid;
+use openssl::pkey::*;
+use openssl::hash::MessageDigest;
+use openssl::bn::BigNum;
+use openssl::asn1::Asn1Time;
+
+use crate::prelude::*;
+
+/**
+ * Loads all certificates from a PEM file, e.g. a  
+ * certificate chain (leaf first) or a CA bundle.
+ */
+pub fn load_certificates(path: &str) -> Result<Vec<X509>, String> {
+    let pem_res = fs::read(path);
+    if pem_res.is_err() {
+        return Err(format!("Could not read certificate file {} !", path));
+    }
+    let certificates_res = X509::stack_from_pem(pem_res.unwrap().as_slice());
+    if certificates_res.is_err() {
+        return Err(format!("Error decoding PEM to certificates in {} !", path));
+    }
+    let certificates = certificates_res.unwrap();
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in {} !", path));
+    }
+    Ok(
+        certificates
+    )
+}
+
+/**
+ * Builds a store of trusted CA certificates.
+ */
+pub fn build_store(ca_certificates: Vec<X509>) -> Result<X509Store, String> {
+    let builder_res = X509StoreBuilder::new();
+    if builder_res.is_err() {
+        return Err("Error creating certificate store!".to_string());
+    }
+    let mut builder = builder_res.unwrap();
+    for certificate in ca_certificates {
+        if builder.add_cert(certificate).is_err() {
+            return Err("Error adding CA certificate to store!".to_string());
+        }
+    }
+    Ok(
+        builder.build()
+    )
+}
+
+/**
+ * Verifies a certificate chain (binary DER, leaf first) against the trusted  
+ * CA certificates, including their validity period, and optionally checks  
+ * that the leaf certificate was issued for the expected name.  
+ * Returns the leaf certificate.
+ */
+pub fn verify_chain(store: &X509StoreRef, chain_der: &[Vec<u8>], name: Option<&String>) -> Result<X509, String> {
+    let mut certificates = Vec::new();
+    for der in chain_der.iter() {
+        let certificate_res = X509::from_der(der.as_slice());
+        if certificate_res.is_err() {
+            return Err("Error decoding DER to certificate!".to_string());
+        }
+        certificates.push(certificate_res.unwrap());
+    }
+    if certificates.is_empty() {
+        return Err("Empty certificate chain!".to_string());
+    }
+    let leaf = certificates.remove(0);
+    let verified_res = Stack::new().and_then(|mut chain| {
+        for certificate in certificates {
+            chain.push(certificate)?;
+        }
+        let mut context = X509StoreContext::new()?;
+        context.init(store, &leaf, &chain, |context| context.verify_cert())
+    });
+    if verified_res.is_err() || !verified_res.unwrap() {
+        return Err("Certificate chain could not be verified!".to_string());
+    }
+    if let Some(name) = name {
+        if !matches_name(&leaf, name) {
+            return Err(format!("Certificate was not issued for {} !", name));
+        }
+    }
+    Ok(
+        leaf
+    )
+}
+
+/**
+ * Checks whether a certificate was issued for a host name, IP address or subject.  
+ * The subject alternative names are checked first, the common name of the subject  
+ * is only used if there are none.
+ */
+pub fn matches_name(certificate: &X509Ref, name: &String) -> bool {
+    let ip_address = name.parse::<IpAddr>().ok();
+    if let Some(alt_names) = certificate.subject_alt_names() {
+        return alt_names.iter().any(|alt_name| {
+            if let Some(dns_name) = alt_name.dnsname() {
+                return dns_name.eq_ignore_ascii_case(name);
+            }
+            match (alt_name.ipaddress(), ip_address) {
+                (Some(bytes), Some(IpAddr::V4(ip))) => bytes == &ip.octets()[..],
+                (Some(bytes), Some(IpAddr::V6(ip))) => bytes == &ip.octets()[..],
+                _ => false
+            }
+        });
+    }
+    certificate.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().to_string().ok())
+        .any(|common_name| common_name.eq_ignore_ascii_case(name))
+}
+
+/**
+ * Creates a self-signed certificate for an RSA or Ed25519 identity key,  
+ * valid for a year. Used by DTLS sessions if no certificate chain is configured,  
+ * remote endpoints can still pin its public key with the known hosts.
+ */
+pub fn self_signed_certificate(private_key: &PKey<Private>) -> Result<X509, String> {
+    // Ed25519 signs the certificate itself, without a separate digest
+    let digest = if private_key.id() == Id::ED25519 {
+        MessageDigest::null()
+    } else {
+        MessageDigest::sha256()
+    };
+    let certificate_res = X509NameBuilder::new().and_then(|mut name| {
+        name.append_entry_by_nid(Nid::COMMONNAME, "udps")?;
+        let name = name.build();
+        let serial = BigNum::from_slice(generate_random_bytes(16).as_slice())?.to_asn1_integer()?;
+        let mut builder = X509Builder::new()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(365)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        builder.set_pubkey(private_key)?;
+        builder.sign(private_key, digest)?;
+        Ok(builder.build())
+    });
+    if certificate_res.is_err() {
+        return Err("Error creating self-signed certificate!".to_string());
+    }
+    Ok(
+        certificate_res.unwrap()
+    )
+}
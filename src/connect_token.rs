@@ -0,0 +1,128 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec::*;
+
+use rmps::*;
+
+use crate::prelude::*;
+
+/**
+ * Associated data binding sealed tokens to their purpose
+ */
+const CONNECT_TOKEN_AAD: &[u8] = b"udps connect token";
+
+/**
+ * Connect token authorizing a client to connect to a set of servers.  
+ * Tokens are minted and sealed by a backend with a key shared with the servers  
+ * (`EndpointConfig::connect_token_key`), handed to the client, and sent by it  
+ * with the `Connect` package. The client can not read or alter them.
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectToken {
+    /**
+     * Application defined id of the client
+     */
+    pub client_id: u64,
+    /**
+     * Expiry as seconds since the UNIX epoch
+     */
+    pub expires_at: u64,
+    /**
+     * Addresses of the servers the client may connect to
+     */
+    pub server_addresses: Vec<String>,
+    /**
+     * Application defined data, exposed as `Connection::user_data`
+     */
+    pub user_data: Vec<u8>,
+    /**
+     * Random key (32 bytes) handed to the client together with the sealed token.  
+     * It is mixed into the secret key, so only the client the token  
+     * was issued to can use a connection made with it
+     */
+    pub client_key: Vec<u8>,
+}
+
+/**
+ * Wiping the client key once the token is sealed or checked
+ */
+impl Drop for ConnectToken {
+    fn drop(&mut self) {
+        zeroize(self.client_key.as_mut_slice());
+    }
+}
+
+impl ConnectToken {
+    /**
+     * Creates a new token with a random client key, valid for the given duration from now on.
+     */
+    pub fn new(client_id: u64, server_addresses: Vec<String>, user_data: Vec<u8>, valid_for: Duration) -> Self {
+        Self {
+            client_id: client_id,
+            expires_at: unix_time() + valid_for.as_secs(),
+            server_addresses: server_addresses,
+            user_data: user_data,
+            client_key: generate_random_bytes(KEY_LEN)
+        }
+    }
+
+    /**
+     * Mixes the client key of a token into a secret key established by the handshake.
+     */
+    pub fn bind_secret(client_key: &Secret, secret_key: &Secret) -> Result<Secret, Error> {
+        let mut info = b"udps connect token".to_vec();
+        info.extend_from_slice(secret_key.as_bytes());
+        let bound_res = hmac_sha256(client_key.as_bytes(), info.as_slice());
+        zeroize(info.as_mut_slice());
+        bound_res.map(Secret::new)
+    }
+
+    /**
+     * Encodes and seals the token with the shared key (AES-256-GCM).
+     */
+    pub fn seal(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let encode_res = to_vec(self);
+        if encode_res.is_err() {
+            return Err("Unknown error encoding MessagePack connect token!".to_string());
+        }
+        CipherSuite::Aes256Gcm.cipher().seal(key, CONNECT_TOKEN_AAD, encode_res.unwrap().as_slice())
+    }
+
+    /**
+     * Opens and decodes a sealed token with the shared key.  
+     * Fails if the token was not sealed with this key or altered.
+     */
+    pub fn open(key: &[u8], sealed: &[u8]) -> Result<Self, Error> {
+        let data = CipherSuite::Aes256Gcm.cipher().open(key, CONNECT_TOKEN_AAD, sealed)?;
+        let token_res = from_slice::<Self>(data.as_slice());
+        if token_res.is_err() {
+            return Err("Unknown error decoding MessagePack connect token!".to_string());
+        }
+        Ok(
+            token_res.unwrap()
+        )
+    }
+
+    /**
+     * Returns true if the token expired.
+     */
+    pub fn is_expired(&self) -> bool {
+        unix_time() >= self.expires_at
+    }
+
+    /**
+     * Returns true if the token allows connecting to one of the given addresses.
+     */
+    pub fn allows(&self, addresses: &[String]) -> bool {
+        self.server_addresses.iter().any(|address| addresses.contains(address))
+    }
+}
+
+/**
+ * Returns the current time as seconds since the UNIX epoch.
+ */
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
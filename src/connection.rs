@@ -1,14 +1,19 @@
 use std::sync::*;
 use std::sync::atomic::*;
+use std::time::{
+    Instant,
+    Duration
+};
 use std::collections::{
+    HashMap,
     HashSet,
     VecDeque
 };
 use std::iter::IntoIterator;
 use std::ops::DerefMut;
 
-use openssl::rsa::*;
 use openssl::pkey::*;
+use openssl::x509::X509;
 
 use crate::prelude::*;
 
@@ -27,17 +32,119 @@ pub struct Connection {
      */
     pub address: String,
     /**
-     * Public key to encode decode key with
+     * True if this endpoint initiated the connection,
+     * which makes it responsible for generating the secret key
+     */
+    pub initiator: bool,
+    /**
+     * Public identity key of the remote endpoint (RSA or Ed25519),  
+     * RSA keys are used to encrypt the secret key in `KeyExchange::Rsa` mode
+     */
+    pub public_key: RwLock<Option<PKey<Public>>>,
+    /**
+     * Leaf certificate of the remote endpoint, if it sent one,  
+     * only verified against the CAs if `trusted_ca` is configured
+     */
+    pub certificate: RwLock<Option<X509>>,
+    /**
+     * Result of checking the public key against the known hosts
      */
-    pub public_key: RwLock<Option<Rsa<Public>>>,
+    pub host_key_status: RwLock<HostKeyStatus>,
     /**
      * Secret key to decode data with
      */
-    pub secret_key: RwLock<Option<Vec<u8>>>,
+    pub secret_key: RwLock<Option<Secret>>,
+    /**
+     * Ephemeral X25519 key, only kept until the secret key is derived
+     */
+    pub ephemeral_key: RwLock<Option<PKey<Private>>>,
+    /**
+     * Nonce sent with the `Connect` package, or the `AsymmKey` package in `KeyExchange::Rsa` mode,  
+     * only kept until the secret key is derived
+     */
+    pub handshake_nonce: RwLock<Option<Vec<u8>>>,
+    /**
+     * Nonce of the remote endpoint in `KeyExchange::Rsa` mode, only kept until the secret key is exchanged
+     */
+    pub remote_handshake_nonce: RwLock<Option<Vec<u8>>>,
+    /**
+     * Resumption ticket sent with our `Connect` package, only kept until the remote endpoint answered
+     */
+    pub ticket: RwLock<Option<Arc<SessionTicket>>>,
+    /**
+     * True once we sent the remote endpoint a resumption ticket
+     */
+    pub ticket_issued: AtomicBool,
+    /**
+     * True if the secret key was derived from a resumption ticket instead of a full handshake
+     */
+    pub resumed: AtomicBool,
+    /**
+     * Noise handshake in progress (`KeyExchange::Noise` only), dropped once it is finished
+     */
+    pub noise: RwLock<Option<NoiseHandshake>>,
+    /**
+     * First Noise message sent with the `Connect` package, only kept until the handshake is finished
+     */
+    pub noise_message: RwLock<Option<Vec<u8>>>,
+    /**
+     * Generation of the secret key, increased with every rekeying
+     */
+    pub key_epoch: AtomicU32,
+    /**
+     * Previous generation of the secret key and the time  
+     * until packages sealed with it are still accepted
+     */
+    pub previous_secret: RwLock<Option<(u32, Secret, Instant)>>,
+    /**
+     * Next generation of the secret key offered to the  
+     * remote endpoint, but not acknowledged yet
+     */
+    pub pending_secret: RwLock<Option<(u32, Secret)>>,
+    /**
+     * Session keys derived from the secret keys above by generation,  
+     * derived once when a secret key is set, rotated or offered
+     */
+    pub session_keys: RwLock<HashMap<u32, Arc<SessionKeys>>>,
+    /**
+     * Number of packages sealed or opened with the current secret key
+     */
+    pub packages_since_rekey: AtomicU64,
+    /**
+     * Number of bytes sealed or opened with the current secret key
+     */
+    pub bytes_since_rekey: AtomicU64,
+    /**
+     * Time the current secret key was established
+     */
+    pub keyed_at: RwLock<Instant>,
+    /**
+     * Cipher suite negotiated during the handshake
+     */
+    pub cipher_suite: RwLock<Option<CipherSuite>>,
     /**
      * Self-explanatory
      */
     pub ping: AtomicU32,
+    /**
+     * Number of received packages dropped because they could  
+     * not be decrypted, were tampered with or replayed
+     */
+    pub dropped_packages: AtomicU32,
+    /**
+     * True once the remote endpoint proved it receives packages at its address,  
+     * by returning a cookie or acknowledging one of our packages.  
+     * Always true for the initiating endpoint.
+     */
+    pub address_validated: AtomicBool,
+    /**
+     * Bytes received from the remote address before it was validated
+     */
+    pub unvalidated_bytes_received: AtomicU64,
+    /**
+     * Bytes sent to the remote address before it was validated
+     */
+    pub unvalidated_bytes_sent: AtomicU64,
     /**
      * Current connection state
      */
@@ -46,6 +153,53 @@ pub struct Connection {
      * Current possible/enabled level of encryption
      */
     pub crypt_state: RwLock<CryptState>,
+    /**
+     * Sealed connect token sent with our `Connect` package, if any
+     */
+    pub connect_token: Option<Vec<u8>>,
+    /**
+     * Client key of the connect token of this connection, mixed into every secret key set
+     */
+    pub connect_token_key: Option<Secret>,
+    /**
+     * Application data sent with our `Connect` package, if any
+     */
+    pub early_data: Option<Vec<u8>>,
+    /**
+     * True if the early data sent with the `Connect` package was accepted,  
+     * always false if the initiating endpoint could not learn it
+     */
+    pub early_data_accepted: AtomicBool,
+    /**
+     * Client id of the connect token the remote endpoint connected with
+     */
+    pub client_id: Option<u64>,
+    /**
+     * User data of the connect token the remote endpoint connected with
+     */
+    pub user_data: Option<Vec<u8>>,
+    /**
+     * True once the remote endpoint proved that it knows the client key  
+     * of its connect token, by a package sealed or tagged with the secret key
+     */
+    pub connect_token_proven: AtomicBool,
+    /**
+     * Secret for keying material exported to the application,  
+     * derived from the first secret key
+     */
+    pub exporter_secret: RwLock<Option<Secret>>,
+    /**
+     * Cookie the accepting endpoint expects in our `Connect` package
+     */
+    pub cookie: RwLock<Option<Vec<u8>>>,
+    /**
+     * Packet number of the next sealed package we send
+     */
+    pub next_packet_number: AtomicU64,
+    /**
+     * Packet numbers of the sealed packages received so far
+     */
+    pub replay_window: RwLock<ReplayWindow>,
     /**
      * Package journal for O(1) lookup of packages in queue
      */
@@ -65,7 +219,9 @@ pub enum ConnectionState {
     Connected,
 }
 /**
- * Unused
+ * Enum for storing the handshake progress of individual connections.  
+ * `None` until the remote public key is known, `Asymm` until the  
+ * secret key has been exchanged and `Symm` afterwards.
  */
 #[derive(PartialEq)]
 pub enum CryptState {
@@ -82,15 +238,55 @@ impl Connection {
         Connection {
             id: connection_id.clone(),
             address: addr.clone(),
+            initiator: false,
             ping: AtomicU32::new(0),
+            dropped_packages: AtomicU32::new(0),
+            address_validated: AtomicBool::new(false),
+            unvalidated_bytes_received: AtomicU64::new(0),
+            unvalidated_bytes_sent: AtomicU64::new(0),
             public_key: RwLock::new(None),
+            certificate: RwLock::new(None),
+            host_key_status: RwLock::new(HostKeyStatus::Unknown),
             secret_key: RwLock::new(None),
+            ephemeral_key: RwLock::new(None),
+            handshake_nonce: RwLock::new(None),
+            remote_handshake_nonce: RwLock::new(None),
+            ticket: RwLock::new(None),
+            ticket_issued: AtomicBool::new(false),
+            resumed: AtomicBool::new(false),
+            noise: RwLock::new(None),
+            noise_message: RwLock::new(None),
+            key_epoch: AtomicU32::new(0),
+            previous_secret: RwLock::new(None),
+            pending_secret: RwLock::new(None),
+            session_keys: RwLock::new(HashMap::new()),
+            packages_since_rekey: AtomicU64::new(0),
+            bytes_since_rekey: AtomicU64::new(0),
+            keyed_at: RwLock::new(Instant::now()),
+            cipher_suite: RwLock::new(None),
             state: RwLock::new(
                 ConnectionState::Disconnected
             ),
             crypt_state: RwLock::new(
                 CryptState::None
             ),
+            connect_token: None,
+            connect_token_key: None,
+            early_data: None,
+            early_data_accepted: AtomicBool::new(false),
+            client_id: None,
+            user_data: None,
+            connect_token_proven: AtomicBool::new(false),
+            exporter_secret: RwLock::new(
+                None
+            ),
+            cookie: RwLock::new(
+                None
+            ),
+            next_packet_number: AtomicU64::new(0),
+            replay_window: RwLock::new(
+                ReplayWindow::new()
+            ),
             package_journal: RwLock::new(
                 HashSet::new()
             ),
@@ -124,19 +320,19 @@ impl Connection {
      * Sets the connections public key from binary DER.
      */
     pub fn set_public_key(&self, pubkey_der: Vec<u8>) -> Result<(), String> {
-        let rsa_pubkey_res = Rsa::public_key_from_der(pubkey_der.as_slice());
-        if rsa_pubkey_res.is_err() {
+        let pubkey_res = PKey::public_key_from_der(pubkey_der.as_slice());
+        if pubkey_res.is_err() {
             return Err("Error decoding DER to public key!".to_string());
         }
         let mut public_key = self.public_key.write().unwrap();
-        *public_key = Some(rsa_pubkey_res.unwrap());
+        *public_key = Some(pubkey_res.unwrap());
         Ok(()) 
     }
 
     /**
      * Gets the connections public key.
      */
-    pub fn get_public_key(&self) -> Option<Rsa<Public>> {
+    pub fn get_public_key(&self) -> Option<PKey<Public>> {
         let pubkey = self.public_key.read().unwrap();
         pubkey.clone()
     }
@@ -144,18 +340,186 @@ impl Connection {
     /**
      * Sets the connections decoding key.
      */
-    pub fn set_secret(&self, secret_key: Vec<u8>) {
+    pub fn set_secret(&self, secret_key: Secret) {
+        // Only the client a connect token was issued to knows its key. Should mixing it in
+        // ever fail, a random secret key makes sure the connection is of no use to anybody
+        let secret_key = match self.connect_token_key {
+            Some(ref client_key) => ConnectToken::bind_secret(client_key, &secret_key)
+                .unwrap_or_else(|_| Secret::random(KEY_LEN)),
+            None => secret_key
+        };
+        let session_keys = SessionKeys::derive(secret_key.as_bytes()).ok().map(Arc::new);
+        // Exported keying material stays the same when the secret key is renewed
+        *self.exporter_secret.write().unwrap() = session_keys.as_ref()
+            .map(|session_keys| session_keys.exporter_secret().duplicate());
         let mut dec_secret = self.secret_key.write().unwrap();
+        let mut keys = self.session_keys.write().unwrap();
+        keys.clear();
+        if let Some(session_keys) = session_keys {
+            keys.insert(self.key_epoch.load(Ordering::Relaxed), session_keys);
+        }
         *dec_secret = Some(secret_key);
+        self.reset_usage();
     }
 
     /**
      * Gets the connections decoding key.
      */
-    pub fn get_secret(&self) -> Vec<u8> {
+    pub fn get_secret(&self) -> Secret {
         let dec_secret_guard = self.secret_key.read().unwrap();
-        let dec_secret = dec_secret_guard.clone();
-        dec_secret.unwrap()
+        dec_secret_guard.as_ref().unwrap().duplicate()
+    }
+
+    /**
+     * Gets the current secret key together with its generation.
+     */
+    pub fn get_current_secret(&self) -> Option<(u32, Secret)> {
+        let secret_key = self.secret_key.read().unwrap();
+        secret_key.as_ref().map(|secret| {
+            (self.key_epoch.load(Ordering::Relaxed), secret.duplicate())
+        })
+    }
+
+    /**
+     * Gets the secret key of a generation, which is either the current one,  
+     * the previous one until the overlap after rekeying has passed,  
+     * or the next one offered to the remote endpoint.
+     */
+    pub fn get_secret_for_epoch(&self, epoch: u32) -> Option<Secret> {
+        {
+            let secret_key = self.secret_key.read().unwrap();
+            if self.key_epoch.load(Ordering::Relaxed) == epoch {
+                return secret_key.as_ref().map(|secret| secret.duplicate());
+            }
+        }
+        if let Some((previous_epoch, ref secret, valid_until)) = *self.previous_secret.read().unwrap() {
+            if previous_epoch == epoch && Instant::now() < valid_until {
+                return Some(secret.duplicate());
+            }
+        }
+        if let Some((pending_epoch, ref secret)) = *self.pending_secret.read().unwrap() {
+            if pending_epoch == epoch {
+                return Some(secret.duplicate());
+            }
+        }
+        None
+    }
+
+    /**
+     * Gets the session keys of the current secret key together with its generation.
+     */
+    pub fn get_current_session_keys(&self) -> Option<(u32, Arc<SessionKeys>)> {
+        let _secret_key = self.secret_key.read().unwrap();
+        let epoch = self.key_epoch.load(Ordering::Relaxed);
+        self.session_keys.read().unwrap().get(&epoch).map(|session_keys| {
+            (epoch, session_keys.clone())
+        })
+    }
+
+    /**
+     * Gets the session keys of a generation, valid for the same  
+     * generations as `get_secret_for_epoch`.
+     */
+    pub fn get_session_keys_for_epoch(&self, epoch: u32) -> Option<Arc<SessionKeys>> {
+        let valid = {
+            let secret_key = self.secret_key.read().unwrap();
+            self.key_epoch.load(Ordering::Relaxed) == epoch && secret_key.is_some()
+        } || match *self.previous_secret.read().unwrap() {
+            Some((previous_epoch, _, valid_until)) => previous_epoch == epoch && Instant::now() < valid_until,
+            None => false
+        } || match *self.pending_secret.read().unwrap() {
+            Some((pending_epoch, _)) => pending_epoch == epoch,
+            None => false
+        };
+        if !valid {
+            return None;
+        }
+        self.session_keys.read().unwrap().get(&epoch).cloned()
+    }
+
+    /**
+     * Offers the next generation of the secret key to the remote endpoint,  
+     * so packages it seals with it can be opened before it is acknowledged.
+     */
+    pub fn offer_secret(&self, epoch: u32, secret_key: Secret) -> Result<(), String> {
+        let session_keys = SessionKeys::derive(secret_key.as_bytes())?;
+        self.session_keys.write().unwrap().insert(epoch, Arc::new(session_keys));
+        *self.pending_secret.write().unwrap() = Some((epoch, secret_key));
+        Ok(())
+    }
+
+    /**
+     * Replaces the secret key with its next generation, keeping the  
+     * previous one for the overlap so packages in flight can still be opened.
+     */
+    pub fn rotate_secret(&self, epoch: u32, secret_key: Secret, overlap: Duration) {
+        let session_keys = SessionKeys::derive(secret_key.as_bytes()).ok().map(Arc::new);
+        let mut current = self.secret_key.write().unwrap();
+        let previous = current.take();
+        let previous_epoch = self.key_epoch.load(Ordering::Relaxed);
+        if let Some(previous) = previous {
+            *self.previous_secret.write().unwrap() = Some(
+                (previous_epoch, previous, Instant::now() + overlap)
+            );
+        }
+        {
+            // Only the previous generation is kept, an offered one is derived again when acknowledged
+            let mut keys = self.session_keys.write().unwrap();
+            keys.retain(|key_epoch, _| *key_epoch == previous_epoch);
+            if let Some(session_keys) = session_keys {
+                keys.insert(epoch, session_keys);
+            }
+        }
+        *current = Some(secret_key);
+        self.key_epoch.store(epoch, Ordering::Relaxed);
+        self.reset_usage();
+    }
+
+    /**
+     * Counts a package sealed or opened with the current secret key.
+     */
+    pub fn record_usage(&self, bytes: usize) {
+        self.packages_since_rekey.fetch_add(1, Ordering::Relaxed);
+        self.bytes_since_rekey.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /**
+     * Resets the usage counters after a new secret key was established.
+     */
+    fn reset_usage(&self) {
+        self.packages_since_rekey.store(0, Ordering::Relaxed);
+        self.bytes_since_rekey.store(0, Ordering::Relaxed);
+        *self.keyed_at.write().unwrap() = Instant::now();
+    }
+
+    /**
+     * Derives keying material for the application bound to this connection,  
+     * like the keying material exporters of TLS. Both endpoints get the same  
+     * bytes for the same label and context, nobody else can compute them.
+     */
+    pub fn export_keying_material(&self, label: &str, context: &[u8], len: usize) -> Result<Vec<u8>, String> {
+        let exporter_secret = self.exporter_secret.read().unwrap();
+        if exporter_secret.is_none() {
+            return Err("No secret key exchanged yet!".to_string());
+        }
+        let mut info = (label.len() as u64).to_le_bytes().to_vec();
+        info.extend_from_slice(label.as_bytes());
+        info.extend_from_slice(context);
+        hkdf_expand(exporter_secret.as_ref().unwrap().as_bytes(), info.as_slice(), len)
+    }
+
+    /**
+     * Returns true if a secret key has been exchanged.
+     */
+    pub fn has_secret(&self) -> bool {
+        self.secret_key.read().unwrap().is_some()
+    }
+
+    /**
+     * Gets the negotiated cipher suite.
+     */
+    pub fn get_cipher_suite(&self) -> Option<CipherSuite> {
+        *self.cipher_suite.read().unwrap()
     }
 
     /**
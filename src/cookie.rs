@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+
+/**
+ * Secrets used to create and check stateless cookies.  
+ * A cookie is a MAC of the remote address and connection id, so the accepting  
+ * endpoint can check that a `Connect` package really comes from the address it  
+ * claims without keeping any state. The secret is rotated periodically and cookies  
+ * made with the previous secret stay valid until the next rotation.
+ */
+pub struct CookieJar {
+    /**
+     * Secret used for new cookies
+     */
+    secret: Secret,
+    /**
+     * Secret before the last rotation
+     */
+    previous_secret: Option<Secret>,
+    /**
+     * Time of the last rotation
+     */
+    rotated_at: Instant,
+}
+
+impl CookieJar {
+    /**
+     * Creates a new cookie jar with a random secret.
+     */
+    pub fn new() -> Self {
+        Self {
+            secret: Secret::random(KEY_LEN),
+            previous_secret: None,
+            rotated_at: Instant::now()
+        }
+    }
+
+    /**
+     * Replaces the secret with a new random one if it is older than `interval`.
+     */
+    pub fn rotate_if_due(&mut self, interval: Duration) {
+        if self.rotated_at.elapsed() < interval {
+            return;
+        }
+        let secret = Secret::random(KEY_LEN);
+        self.previous_secret = Some(std::mem::replace(&mut self.secret, secret));
+        self.rotated_at = Instant::now();
+    }
+
+    /**
+     * Creates the cookie for a connection request from `address`.
+     */
+    pub fn generate(&self, address: &String, connection_id: u32) -> Result<Vec<u8>, Error> {
+        Self::compute(self.secret.as_bytes(), address, connection_id)
+    }
+
+    /**
+     * Checks a cookie returned with a connection request from `address`.
+     */
+    pub fn verify(&self, address: &String, connection_id: u32, cookie: &[u8]) -> bool {
+        let secrets = Some(&self.secret).into_iter().chain(self.previous_secret.as_ref());
+        for secret in secrets {
+            let expected_res = Self::compute(secret.as_bytes(), address, connection_id);
+            if expected_res.is_ok() && constant_time_eq(expected_res.unwrap().as_slice(), cookie) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn compute(secret: &[u8], address: &String, connection_id: u32) -> Result<Vec<u8>, Error> {
+        let mut data = b"udps cookie".to_vec();
+        data.extend_from_slice(&connection_id.to_le_bytes());
+        data.extend_from_slice(address.as_bytes());
+        hmac_sha256(secret, data.as_slice())
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_cookies_for_address_and_connection() {
+        let jar = CookieJar::new();
+        let address = "127.0.0.1:4000".to_string();
+        let cookie = jar.generate(&address, 7).unwrap();
+        assert!(jar.verify(&address, 7, cookie.as_slice()));
+        assert!(!jar.verify(&address, 8, cookie.as_slice()));
+        assert!(!jar.verify(&"127.0.0.1:4001".to_string(), 7, cookie.as_slice()));
+        assert!(!jar.verify(&address, 7, &cookie[1..]));
+    }
+
+    #[test]
+    fn keeps_previous_secret_for_one_rotation() {
+        let mut jar = CookieJar::new();
+        let address = "127.0.0.1:4000".to_string();
+        let cookie = jar.generate(&address, 7).unwrap();
+        jar.rotate_if_due(Duration::from_secs(60));
+        assert!(jar.verify(&address, 7, cookie.as_slice()));
+        jar.rotate_if_due(Duration::from_secs(0));
+        assert!(jar.verify(&address, 7, cookie.as_slice()));
+        jar.rotate_if_due(Duration::from_secs(0));
+        assert!(!jar.verify(&address, 7, cookie.as_slice()));
+    }
+}
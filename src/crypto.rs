@@ -0,0 +1,642 @@
+use std::vec::*;
+
+use openssl::symm;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{
+    PKey,
+    Id,
+    Private,
+    Public
+};
+use openssl::derive::Deriver;
+use openssl::hash::hash;
+use openssl::sign::{
+    Signer,
+    Verifier
+};
+use openssl::memcmp;
+use twofish::Twofish;
+use twofish::cipher::{
+    KeyInit,
+    BlockCipherEncrypt,
+    Block
+};
+
+use crate::prelude::*;
+
+/**
+ * Length of the nonce of sealed data
+ */
+pub const NONCE_LEN: usize = 12;
+/**
+ * Length of the authentication tag appended to sealed data
+ */
+pub const TAG_LEN: usize = 16;
+/**
+ * Length of the secret key shared by both endpoints
+ */
+pub const KEY_LEN: usize = 32;
+/**
+ * Length of the HMAC-SHA256 tag appended to authenticated (`CryptType::Auth`) packages
+ */
+pub const AUTH_TAG_LEN: usize = 32;
+
+/**
+ * Authenticated encryption algorithm used to seal packages.  
+ * Sealed data consists of the ciphertext followed by a `TAG_LEN` byte tag,  
+ * `seal` and `open` additionally prepend a random `NONCE_LEN` byte nonce.
+ */
+pub trait Cipher {
+    /**
+     * Encrypts and authenticates data with a `KEY_LEN` byte key and a nonce  
+     * which must never be used twice with the same key.  
+     * The associated data is authenticated, but neither encrypted nor included.
+     */
+    fn seal_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String>;
+    /**
+     * Decrypts data sealed with `seal_with_nonce`, failing if it or the associated  
+     * data has been tampered with or it was sealed with another key or nonce.
+     */
+    fn open_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String>;
+
+    /**
+     * Encrypts and authenticates data with a `KEY_LEN` byte key  
+     * and a random nonce, which is prepended to the sealed data.
+     */
+    fn seal(&self, key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let mut sealed = generate_random_bytes(NONCE_LEN);
+        let ciphertext = self.seal_with_nonce(key, sealed.as_slice(), aad, plaintext)?;
+        sealed.extend_from_slice(ciphertext.as_slice());
+        Ok(
+            sealed
+        )
+    }
+
+    /**
+     * Decrypts data sealed with `seal`.
+     */
+    fn open(&self, key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < NONCE_LEN {
+            return Err("Sealed data is too short!".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.open_with_nonce(key, nonce, aad, ciphertext)
+    }
+}
+
+/**
+ * Cipher suites which can be negotiated during the handshake
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CipherSuite {
+    Aes256Gcm = 0,
+    ChaCha20Poly1305,
+    Twofish256CtrHmacSha256,
+}
+
+/**
+ * AES-256-GCM, the fastest choice on hardware with AES-NI
+ */
+pub struct AesGcmCipher;
+/**
+ * ChaCha20-Poly1305, for hardware without AES acceleration
+ */
+pub struct ChaChaPolyCipher;
+/**
+ * Twofish-256 in counter mode, authenticated with HMAC-SHA256  
+ * (encrypt-then-MAC, tag truncated to `TAG_LEN` bytes)
+ */
+pub struct TwofishCipher;
+
+impl CipherSuite {
+    /**
+     * Returns all supported cipher suites in order of preference.
+     */
+    pub fn all() -> Vec<Self> {
+        vec![
+            CipherSuite::Aes256Gcm,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Twofish256CtrHmacSha256
+        ]
+    }
+
+    /**
+     * Returns the cipher implementing this suite.
+     */
+    pub fn cipher(&self) -> &'static dyn Cipher {
+        match self {
+            CipherSuite::Aes256Gcm => &AesGcmCipher,
+            CipherSuite::ChaCha20Poly1305 => &ChaChaPolyCipher,
+            CipherSuite::Twofish256CtrHmacSha256 => &TwofishCipher
+        }
+    }
+
+    /**
+     * Picks the first of the offered suites that is also supported,  
+     * so the preference of the offering endpoint wins.
+     */
+    pub fn negotiate(offered: &[CipherSuite], supported: &[CipherSuite]) -> Option<CipherSuite> {
+        offered.iter().find(|suite| supported.contains(suite)).cloned()
+    }
+}
+
+/**
+ * Seals data with one of openssls AEAD ciphers.
+ */
+fn seal_aead(cipher: symm::Cipher, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut tag = [0u8; TAG_LEN];
+    let encrypt_res = symm::encrypt_aead(
+        cipher,
+        key,
+        Some(nonce),
+        aad,
+        plaintext,
+        &mut tag
+    );
+    if encrypt_res.is_err() {
+        return Err("Error encrypting data!".to_string());
+    }
+    let mut sealed = encrypt_res.unwrap();
+    sealed.extend_from_slice(&tag);
+    Ok(
+        sealed
+    )
+}
+
+/**
+ * Opens data sealed with one of openssls AEAD ciphers.
+ */
+fn open_aead(cipher: symm::Cipher, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < TAG_LEN {
+        return Err("Sealed data is too short!".to_string());
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    let decrypt_res = symm::decrypt_aead(
+        cipher,
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag
+    );
+    if decrypt_res.is_err() {
+        return Err("Error decrypting data!".to_string());
+    }
+    Ok(
+        decrypt_res.unwrap()
+    )
+}
+
+impl Cipher for AesGcmCipher {
+    fn seal_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        seal_aead(symm::Cipher::aes_256_gcm(), key, nonce, aad, plaintext)
+    }
+
+    fn open_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        open_aead(symm::Cipher::aes_256_gcm(), key, nonce, aad, sealed)
+    }
+}
+
+impl Cipher for ChaChaPolyCipher {
+    fn seal_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        seal_aead(symm::Cipher::chacha20_poly1305(), key, nonce, aad, plaintext)
+    }
+
+    fn open_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        open_aead(symm::Cipher::chacha20_poly1305(), key, nonce, aad, sealed)
+    }
+}
+
+impl TwofishCipher {
+    /**
+     * Derives independent encryption and MAC keys from the secret key.
+     */
+    fn derive_keys(key: &[u8]) -> Result<(Secret, Secret), String> {
+        let enc_key = Secret::new(hmac_sha256(key, b"udps twofish encryption")?);
+        let mac_key = Secret::new(hmac_sha256(key, b"udps twofish authentication")?);
+        Ok(
+            (enc_key, mac_key)
+        )
+    }
+
+    /**
+     * Applies the Twofish-CTR keystream for the given nonce.  
+     * The counter block is the nonce followed by a 32 bit big endian block counter.
+     */
+    fn apply_keystream(key: &[u8], nonce: &[u8], data: &mut [u8]) -> Result<(), String> {
+        let twofish_res = Twofish::new_from_slice(key);
+        if twofish_res.is_err() {
+            return Err("Invalid Twofish key length!".to_string());
+        }
+        let twofish = twofish_res.unwrap();
+        for (counter, chunk) in data.chunks_mut(16).enumerate() {
+            let mut counter_block = [0u8; 16];
+            counter_block[..NONCE_LEN].copy_from_slice(nonce);
+            counter_block[NONCE_LEN..].copy_from_slice(&(counter as u32).to_be_bytes());
+            let mut block = Block::<Twofish>::from(counter_block);
+            twofish.encrypt_block(&mut block);
+            for (byte, key_byte) in chunk.iter_mut().zip(block.iter()) {
+                *byte ^= key_byte;
+            }
+            zeroize(block.as_mut_slice());
+        }
+        Ok(())
+    }
+
+    /**
+     * Builds the MAC input from the associated data (prefixed  
+     * with its length) followed by the nonce and the ciphertext.
+     */
+    fn mac_input(aad: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+        let mut input = (aad.len() as u64).to_le_bytes().to_vec();
+        input.extend_from_slice(aad);
+        input.extend_from_slice(nonce);
+        input.extend_from_slice(ciphertext);
+        input
+    }
+}
+
+impl Cipher for TwofishCipher {
+    fn seal_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        if nonce.len() != NONCE_LEN {
+            return Err("Invalid nonce length!".to_string());
+        }
+        let (enc_key, mac_key) = Self::derive_keys(key)?;
+        let mut sealed = plaintext.to_vec();
+        Self::apply_keystream(enc_key.as_bytes(), nonce, sealed.as_mut_slice())?;
+        let tag = hmac_sha256(mac_key.as_bytes(), Self::mac_input(aad, nonce, sealed.as_slice()).as_slice())?;
+        sealed.extend_from_slice(&tag[..TAG_LEN]);
+        Ok(
+            sealed
+        )
+    }
+
+    fn open_with_nonce(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if nonce.len() != NONCE_LEN || sealed.len() < TAG_LEN {
+            return Err("Sealed data is too short!".to_string());
+        }
+        let (enc_key, mac_key) = Self::derive_keys(key)?;
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let expected_tag = hmac_sha256(mac_key.as_bytes(), Self::mac_input(aad, nonce, ciphertext).as_slice())?;
+        if !memcmp::eq(&expected_tag[..TAG_LEN], tag) {
+            return Err("Error decrypting data!".to_string());
+        }
+        let mut plaintext = ciphertext.to_vec();
+        Self::apply_keystream(enc_key.as_bytes(), nonce, plaintext.as_mut_slice())?;
+        Ok(
+            plaintext
+        )
+    }
+}
+
+/**
+ * Keys and IVs of both directions of a connection, derived from its  
+ * secret key with HKDF-SHA256. Each direction seals with its own key,  
+ * and the nonce of a package is its packet number XORed into the IV.  
+ * Authenticated packages are tagged with a separate key per direction.
+ */
+pub struct SessionKeys {
+    initiator_key: Secret,
+    initiator_iv: Secret,
+    initiator_auth_key: Secret,
+    acceptor_key: Secret,
+    acceptor_iv: Secret,
+    acceptor_auth_key: Secret,
+    exporter_secret: Secret,
+}
+
+impl SessionKeys {
+    /**
+     * Derives the keys and IVs of both directions from a secret key.
+     */
+    pub fn derive(secret_key: &[u8]) -> Result<Self, String> {
+        let prk = Secret::new(hkdf_extract(b"udps session keys", secret_key)?);
+        let expand = |info: &[u8], len: usize| {
+            hkdf_expand(prk.as_bytes(), info, len).map(Secret::new)
+        };
+        Ok(
+            Self {
+                initiator_key: expand(b"udps initiator key", KEY_LEN)?,
+                initiator_iv: expand(b"udps initiator iv", NONCE_LEN)?,
+                initiator_auth_key: expand(b"udps initiator auth key", KEY_LEN)?,
+                acceptor_key: expand(b"udps acceptor key", KEY_LEN)?,
+                acceptor_iv: expand(b"udps acceptor iv", NONCE_LEN)?,
+                acceptor_auth_key: expand(b"udps acceptor auth key", KEY_LEN)?,
+                exporter_secret: expand(b"udps exporter", KEY_LEN)?
+            }
+        )
+    }
+
+    /**
+     * Returns the key and IV sealing packages sent by the initiating  
+     * endpoint if `initiator` is true, or by the accepting endpoint otherwise.
+     */
+    pub fn direction(&self, initiator: bool) -> (&[u8], &[u8]) {
+        if initiator {
+            (self.initiator_key.as_bytes(), self.initiator_iv.as_bytes())
+        } else {
+            (self.acceptor_key.as_bytes(), self.acceptor_iv.as_bytes())
+        }
+    }
+
+    /**
+     * Returns the key tagging authenticated packages sent by the initiating  
+     * endpoint if `initiator` is true, or by the accepting endpoint otherwise.
+     */
+    pub fn auth_key(&self, initiator: bool) -> &[u8] {
+        if initiator {
+            self.initiator_auth_key.as_bytes()
+        } else {
+            self.acceptor_auth_key.as_bytes()
+        }
+    }
+
+    /**
+     * Computes the tag of an authenticated package,  
+     * the HMAC-SHA256 of its encoded header and payload.
+     */
+    pub fn auth_tag(auth_key: &[u8], associated_data: &[u8], payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut data = (associated_data.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(associated_data);
+        data.extend_from_slice(payload);
+        hmac_sha256(auth_key, data.as_slice())
+    }
+
+    /**
+     * Returns the secret keying material for the application is derived from.
+     */
+    pub fn exporter_secret(&self) -> &Secret {
+        &self.exporter_secret
+    }
+
+    /**
+     * Builds the nonce of a packet number, the big endian  
+     * packet number XORed into the last bytes of the IV.
+     */
+    pub fn nonce(iv: &[u8], packet_number: u64) -> Vec<u8> {
+        let mut nonce = iv.to_vec();
+        let offset = NONCE_LEN - 8;
+        for (byte, number_byte) in nonce[offset..].iter_mut().zip(packet_number.to_be_bytes().iter()) {
+            *byte ^= number_byte;
+        }
+        nonce
+    }
+}
+
+/**
+ * HKDF-Extract (RFC 5869) with HMAC-SHA256.
+ */
+pub fn hkdf_extract(salt: &[u8], input_key: &[u8]) -> Result<Vec<u8>, String> {
+    hmac_sha256(salt, input_key)
+}
+
+/**
+ * HKDF-Expand (RFC 5869) with HMAC-SHA256,  
+ * at most 255 times 32 bytes can be derived.
+ */
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    if len > 255 * 32 {
+        return Err("Too much key material requested!".to_string());
+    }
+    let mut okm = Vec::with_capacity(len);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < len {
+        let mut input = block;
+        input.extend_from_slice(info);
+        input.push(counter);
+        let block_res = hmac_sha256(prk, input.as_slice());
+        zeroize(input.as_mut_slice());
+        block = block_res?;
+        okm.extend_from_slice(block.as_slice());
+        counter = counter.wrapping_add(1);
+    }
+    zeroize(block.as_mut_slice());
+    zeroize(&mut okm[len..]);
+    okm.truncate(len);
+    Ok(
+        okm
+    )
+}
+
+/**
+ * Compares two byte slices in constant time.
+ */
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+/**
+ * Computes the HMAC-SHA256 of data.
+ */
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let pkey_res = PKey::hmac(key);
+    if pkey_res.is_err() {
+        return Err("Invalid HMAC key!".to_string());
+    }
+    let pkey = pkey_res.unwrap();
+    let signer_res = Signer::new(MessageDigest::sha256(), &pkey);
+    if signer_res.is_err() {
+        return Err("Error creating HMAC signer!".to_string());
+    }
+    let mut signer = signer_res.unwrap();
+    let mac_res = signer.update(data).and_then(|_| signer.sign_to_vec());
+    if mac_res.is_err() {
+        return Err("Error computing HMAC!".to_string());
+    }
+    Ok(
+        mac_res.unwrap()
+    )
+}
+
+/**
+ * Generates an ephemeral X25519 key pair.
+ */
+pub fn generate_x25519() -> Result<PKey<Private>, String> {
+    let key_res = PKey::generate_x25519();
+    if key_res.is_err() {
+        return Err("Error generating X25519 key!".to_string());
+    }
+    Ok(
+        key_res.unwrap()
+    )
+}
+
+/**
+ * Gets the raw 32 byte public key of an X25519 key pair.
+ */
+pub fn x25519_public_bytes(key: &PKey<Private>) -> Result<Vec<u8>, String> {
+    let public_res = key.raw_public_key();
+    if public_res.is_err() {
+        return Err("Error encoding X25519 public key!".to_string());
+    }
+    Ok(
+        public_res.unwrap()
+    )
+}
+
+/**
+ * Encodes a raw X25519 public key to binary DER.
+ */
+pub fn x25519_public_der(public: &[u8]) -> Result<Vec<u8>, String> {
+    let der_res = PKey::public_key_from_raw_bytes(public, Id::X25519)
+        .and_then(|key| key.public_key_to_der());
+    if der_res.is_err() {
+        return Err("Invalid X25519 public key!".to_string());
+    }
+    Ok(
+        der_res.unwrap()
+    )
+}
+
+/**
+ * Computes the X25519 shared secret with a raw remote public key.
+ */
+pub fn derive_x25519(key: &PKey<Private>, remote_public: &[u8]) -> Result<Vec<u8>, String> {
+    let remote_res = PKey::public_key_from_raw_bytes(remote_public, Id::X25519);
+    if remote_res.is_err() {
+        return Err("Invalid X25519 public key!".to_string());
+    }
+    let remote = remote_res.unwrap();
+    let shared_res = Deriver::new(key)
+        .and_then(|mut deriver| {
+            deriver.set_peer(&remote)?;
+            deriver.derive_to_vec()
+        });
+    if shared_res.is_err() {
+        return Err("Error deriving X25519 shared secret!".to_string());
+    }
+    Ok(
+        shared_res.unwrap()
+    )
+}
+
+/**
+ * Signs data with a private identity key,  
+ * RSA keys sign with SHA-256, Ed25519 keys sign the data itself.
+ */
+pub fn sign(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, String> {
+    let signature_res = if key.id() == Id::ED25519 {
+        Signer::new_without_digest(key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+    } else {
+        Signer::new(MessageDigest::sha256(), key)
+            .and_then(|mut signer| {
+                signer.update(data)?;
+                signer.sign_to_vec()
+            })
+    };
+    if signature_res.is_err() {
+        return Err("Error signing data!".to_string());
+    }
+    Ok(
+        signature_res.unwrap()
+    )
+}
+
+/**
+ * Verifies a signature created with `sign`.
+ */
+pub fn verify(key: &PKey<Public>, data: &[u8], signature: &[u8]) -> bool {
+    let verify_res = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key)
+            .and_then(|mut verifier| verifier.verify_oneshot(signature, data))
+    } else {
+        Verifier::new(MessageDigest::sha256(), key)
+            .and_then(|mut verifier| {
+                verifier.update(data)?;
+                verifier.verify(signature)
+            })
+    };
+    verify_res.unwrap_or(false)
+}
+
+/**
+ * Computes the fingerprint of a public key (binary DER),  
+ * the lowercase hex encoded SHA-256 hash.
+ */
+pub fn fingerprint(public_key_der: &[u8]) -> String {
+    let digest = hash(MessageDigest::sha256(), public_key_der).unwrap();
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(string: &str) -> Vec<u8> {
+        (0..string.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn seals_and_opens_with_every_suite() {
+        let key = [7u8; KEY_LEN];
+        for suite in CipherSuite::all() {
+            let cipher = suite.cipher();
+            for len in [0, 1, 16, 17, 1000].iter() {
+                let plaintext = vec![0x5a; *len];
+                let sealed = cipher.seal(&key, b"header", plaintext.as_slice()).unwrap();
+                assert_eq!(sealed.len(), NONCE_LEN + len + TAG_LEN);
+                assert_eq!(cipher.open(&key, b"header", sealed.as_slice()).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_data() {
+        let key = [7u8; KEY_LEN];
+        for suite in CipherSuite::all() {
+            let cipher = suite.cipher();
+            let sealed = cipher.seal(&key, b"header", b"payload").unwrap();
+            // Nonce, ciphertext and every byte of the (truncated) tag are authenticated
+            for i in 0..sealed.len() {
+                let mut tampered = sealed.clone();
+                tampered[i] ^= 1;
+                assert!(cipher.open(&key, b"header", tampered.as_slice()).is_err(), "{:?} byte {}", suite, i);
+            }
+            assert!(cipher.open(&key, b"header", &sealed[..sealed.len() - 1]).is_err());
+            assert!(cipher.open(&key, b"header", &sealed[..NONCE_LEN + TAG_LEN - 1]).is_err());
+            assert!(cipher.open(&key, b"headex", sealed.as_slice()).is_err());
+            assert!(cipher.open(&key, b"", sealed.as_slice()).is_err());
+            assert!(cipher.open(&[8u8; KEY_LEN], b"header", sealed.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn twofish_matches_test_vector() {
+        // Twofish-256 with an all-zero key and block, from the Twofish paper,
+        // the first counter block of an all-zero nonce is the all-zero block
+        let mut keystream = [0u8; 16];
+        TwofishCipher::apply_keystream(&[0u8; 32], &[0u8; NONCE_LEN], &mut keystream).unwrap();
+        assert_eq!(keystream.to_vec(), hex("57ff739d4dc92c1bd7fc01700cc8216f"));
+    }
+
+    #[test]
+    fn hkdf_matches_rfc_5869() {
+        let prk = hkdf_extract(&hex("000102030405060708090a0b0c"), &[0x0b; 22]).unwrap();
+        assert_eq!(prk, hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"));
+        let okm = hkdf_expand(prk.as_slice(), &hex("f0f1f2f3f4f5f6f7f8f9"), 42).unwrap();
+        assert_eq!(okm, hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"));
+        assert!(hkdf_expand(prk.as_slice(), &[], 255 * 32 + 1).is_err());
+    }
+
+    #[test]
+    fn derives_known_session_keys() {
+        let session_keys = SessionKeys::derive(&[0x42; KEY_LEN]).unwrap();
+        let (initiator_key, initiator_iv) = session_keys.direction(true);
+        assert_eq!(initiator_key, hex("4e244bcb2fa7b514c206de5078490787b53557363f8a6ffe804a706ec451c6cd").as_slice());
+        assert_eq!(initiator_iv, hex("66008f2bb842632cea22c88f").as_slice());
+        assert_eq!(session_keys.direction(false).0, hex("b4329eebfc04f5923ad7fa17c6925a12b0ee7938622ef981e19334eb7ab83301").as_slice());
+        assert_eq!(session_keys.auth_key(false), hex("04aae62a57677838ab4e7e3ca1da186280c9f1c5f50418bbbc6f74f29dc5d482").as_slice());
+        assert_eq!(session_keys.exporter_secret().as_bytes(), hex("35ecb0a94f10b75be4dd0b33fa88b6c603b9e1b0c92796708e0b5a126c59adc7").as_slice());
+    }
+
+    #[test]
+    fn builds_nonces_from_packet_numbers() {
+        let iv = hex("66008f2bb842632cea22c88f");
+        assert_eq!(SessionKeys::nonce(iv.as_slice(), 0), iv);
+        assert_eq!(SessionKeys::nonce(iv.as_slice(), 0x0102), hex("66008f2bb842632cea22c98d"));
+    }
+}
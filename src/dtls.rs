@@ -0,0 +1,1011 @@
+use std::io;
+use std::io::{
+    Read,
+    Write
+};
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::collections::VecDeque;
+use std::sync::*;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering
+};
+use std::time::{
+    Instant,
+    Duration
+};
+use std::convert::*;
+
+use openssl::ssl::{
+    Ssl,
+    SslContext,
+    SslMethod,
+    SslVersion,
+    SslOptions,
+    SslVerifyMode,
+    SslStream,
+    ShutdownResult,
+    ErrorCode
+};
+use openssl::ex_data::Index;
+use openssl::pkey::Id;
+use openssl::error::ErrorStack;
+use openssl::x509::X509;
+
+use crate::prelude::*;
+
+/**
+ * Maximum size of the datagrams sent by openssl during the DTLS handshake
+ */
+pub const DTLS_MTU: u32 = 1200;
+
+/**
+ * Length of the header of a DTLS record
+ */
+const DTLS_RECORD_HEADER_LEN: usize = 13;
+
+/**
+ * Length of the header of a DTLS handshake message
+ */
+const DTLS_HANDSHAKE_HEADER_LEN: usize = 12;
+
+/**
+ * Transport carrying the packages of an endpoint
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transport {
+    /**
+     * Packages are sent as plain UDP datagrams,  
+     * secured by the UDPS handshake and sealed with the secret key
+     */
+    Udps = 0,
+    /**
+     * Packages are sent through a DTLS 1.2 session with every remote address,  
+     * authenticated with the identity key and certificate chain of the endpoint.  
+     * The UDPS handshake is skipped, connections are secure once the session is established
+     */
+    Dtls,
+}
+
+/**
+ * Datagrams exchanged between openssl and the socket of the endpoint.  
+ * Every read returns one received datagram, every write is sent as one datagram.
+ */
+pub struct DtlsChannel {
+    /**
+     * Received datagrams not read by openssl yet
+     */
+    pub incoming: VecDeque<Vec<u8>>,
+    /**
+     * Datagrams written by openssl, to be sent to the remote address
+     */
+    pub outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for DtlsChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                // Datagrams larger than the buffer are truncated, like with a socket
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            },
+            None => Err(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+    }
+}
+
+impl Write for DtlsChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * Remote endpoint of an established DTLS session
+ */
+pub struct DtlsPeer {
+    /**
+     * Leaf certificate of the remote endpoint, if it sent one
+     */
+    pub certificate: Option<X509>,
+    /**
+     * Public key of the remote endpoint (binary DER), if it sent a certificate
+     */
+    pub public_key: Option<Vec<u8>>,
+    /**
+     * Cipher suite negotiated by openssl
+     */
+    pub cipher_suite: Option<CipherSuite>,
+}
+
+/**
+ * DTLS session with a remote address, shared by all connections to it
+ */
+pub struct DtlsSession {
+    /**
+     * Remote address
+     */
+    pub address: String,
+    /**
+     * True if we started the session
+     */
+    pub initiator: bool,
+    /**
+     * Time the session was started
+     */
+    pub created: Instant,
+    /**
+     * openssl stream
+     */
+    pub stream: RwLock<SslStream<DtlsChannel>>,
+    /**
+     * True once the handshake is finished
+     */
+    pub established: AtomicBool,
+    /**
+     * True once the session is closed or failed
+     */
+    pub closed: AtomicBool,
+    /**
+     * Encoded packages sent before the handshake finished
+     */
+    pub pending: RwLock<Vec<Vec<u8>>>,
+    /**
+     * Remote endpoint, once the handshake finished and it has been verified
+     */
+    pub peer: RwLock<Option<DtlsPeer>>,
+}
+
+/**
+ * ClientHello starting a datagram from an unknown address,  
+ * parsed to check its cookie before keeping any state for the address
+ */
+struct ClientHello {
+    /**
+     * Record carrying the ClientHello
+     */
+    record: Vec<u8>,
+    /**
+     * Position of the cookie length in the record
+     */
+    cookie_pos: usize,
+}
+
+/**
+ * openssl context shared by all DTLS sessions of an endpoint
+ */
+pub struct DtlsContext {
+    /**
+     * openssl context with our identity key and certificate chain
+     */
+    pub context: SslContext,
+    /**
+     * Index of the remote address of a session,  
+     * which the cookie callbacks need
+     */
+    pub address_index: Index<Ssl, String>,
+}
+
+/**
+ * What happened when data was passed to a DTLS session
+ */
+pub struct DtlsProgress {
+    /**
+     * True if the handshake just finished
+     */
+    pub established: bool,
+    /**
+     * Application data received, one encoded package each
+     */
+    pub records: Vec<Vec<u8>>,
+    /**
+     * Datagrams to send to the remote address
+     */
+    pub datagrams: Vec<Vec<u8>>,
+}
+
+/**
+ * Builds the openssl cipher list of the supported cipher suites,  
+ * all with ephemeral ECDH. Twofish is not available in DTLS.
+ */
+pub fn dtls_cipher_list(cipher_suites: &[CipherSuite]) -> String {
+    let mut ciphers = Vec::new();
+    for cipher_suite in cipher_suites.iter() {
+        match *cipher_suite {
+            CipherSuite::Aes256Gcm => {
+                ciphers.push("ECDHE-ECDSA-AES256-GCM-SHA384");
+                ciphers.push("ECDHE-RSA-AES256-GCM-SHA384");
+            },
+            CipherSuite::ChaCha20Poly1305 => {
+                ciphers.push("ECDHE-ECDSA-CHACHA20-POLY1305");
+                ciphers.push("ECDHE-RSA-CHACHA20-POLY1305");
+            },
+            CipherSuite::Twofish256CtrHmacSha256 => {}
+        }
+    }
+    ciphers.join(":")
+}
+
+/**
+ * Resolves an address to the form reported for received datagrams,  
+ * which DTLS sessions are looked up by.
+ */
+pub fn dtls_address(address: &String) -> String {
+    address.to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .map(|address| address.to_string())
+        .unwrap_or(address.clone())
+}
+
+impl ClientHello {
+    /**
+     * Parses the ClientHello at the start of a datagram.  
+     * Returns None if there is none or it is fragmented.
+     */
+    fn parse(datagram: &[u8]) -> Option<Self> {
+        let handshake_pos = DTLS_RECORD_HEADER_LEN;
+        if datagram.len() < handshake_pos + DTLS_HANDSHAKE_HEADER_LEN ||
+            datagram[0] != 22 || datagram[3..5] != [0, 0] || datagram[handshake_pos] != 1 {
+            return None;
+        }
+        let record_len = u16::from_be_bytes([datagram[11], datagram[12]]) as usize;
+        if handshake_pos + record_len > datagram.len() {
+            return None;
+        }
+        let record = &datagram[..handshake_pos + record_len];
+        let message_len = read_u24(&record[handshake_pos + 1..]);
+        let fragment_offset = read_u24(&record[handshake_pos + 6..]);
+        let fragment_len = read_u24(&record[handshake_pos + 9..]);
+        if fragment_offset != 0 || fragment_len != message_len ||
+            DTLS_HANDSHAKE_HEADER_LEN + message_len != record_len {
+            return None;
+        }
+        // The session id follows the client version and random
+        let session_id_pos = handshake_pos + DTLS_HANDSHAKE_HEADER_LEN + 2 + 32;
+        if record.len() <= session_id_pos {
+            return None;
+        }
+        let cookie_pos = session_id_pos + 1 + record[session_id_pos] as usize;
+        if record.len() <= cookie_pos || record.len() < cookie_pos + 1 + record[cookie_pos] as usize {
+            return None;
+        }
+        Some(
+            Self {
+                record: record.to_vec(),
+                cookie_pos: cookie_pos
+            }
+        )
+    }
+
+    /**
+     * Returns the cookie sent with the ClientHello, empty if there is none.
+     */
+    fn cookie(&self) -> &[u8] {
+        &self.record[self.cookie_pos + 1..self.cookie_end()]
+    }
+
+    /**
+     * Builds the HelloVerifyRequest asking the client to send the ClientHello again with a cookie.
+     */
+    fn hello_verify_request(&self, cookie: &[u8]) -> Vec<u8> {
+        let mut body = vec![0xfe, 0xff, cookie.len() as u8];
+        body.extend_from_slice(cookie);
+        // HelloVerifyRequest, unfragmented, answering the record sequence number of the client
+        let mut datagram = vec![22, 0xfe, 0xff, 0, 0];
+        datagram.extend_from_slice(&self.record[5..11]);
+        datagram.extend_from_slice(&((DTLS_HANDSHAKE_HEADER_LEN + body.len()) as u16).to_be_bytes());
+        datagram.push(3);
+        datagram.extend_from_slice(&write_u24(body.len()));
+        datagram.extend_from_slice(&[0, 0, 0, 0, 0]);
+        datagram.extend_from_slice(&write_u24(body.len()));
+        datagram.append(&mut body);
+        datagram
+    }
+
+    /**
+     * Builds the ClientHello the client sent before it got a cookie,  
+     * that is this one without the cookie and with the first sequence numbers.
+     */
+    fn initial(&self) -> Vec<u8> {
+        let mut record = self.record[..self.cookie_pos].to_vec();
+        record.push(0);
+        record.extend_from_slice(&self.record[self.cookie_end()..]);
+        let record_len = record.len() - DTLS_RECORD_HEADER_LEN;
+        let message_len = write_u24(record_len - DTLS_HANDSHAKE_HEADER_LEN);
+        record[5..11].copy_from_slice(&[0; 6]);
+        record[11..13].copy_from_slice(&(record_len as u16).to_be_bytes());
+        record[14..17].copy_from_slice(&message_len);
+        record[17..22].copy_from_slice(&[0; 5]);
+        record[22..25].copy_from_slice(&message_len);
+        record
+    }
+
+    fn cookie_end(&self) -> usize {
+        self.cookie_pos + 1 + self.record[self.cookie_pos] as usize
+    }
+}
+
+/**
+ * Internal function reading a 24 bit big endian length.
+ */
+fn read_u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+/**
+ * Internal function writing a 24 bit big endian length.
+ */
+fn write_u24(len: usize) -> [u8; 3] {
+    [(len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
+
+impl DtlsContext {
+    /**
+     * Creates the openssl context for an endpoint configuration.  
+     * Without a configured certificate chain, a self-signed certificate is used.  
+     * openssl does not support Ed25519 in DTLS 1.2, so the identity key must be an RSA key.  
+     * If `cookie_exchange` is set, servers only continue handshakes with clients  
+     * returning a cookie from the cookie jar, like for `Cookie` packages.
+     */
+    pub fn new(config: &EndpointConfig, cookie_jar: Arc<RwLock<CookieJar>>) -> Result<Self, Error> {
+        let private_key = config.identity_key()?;
+        if private_key.id() == Id::ED25519 {
+            return Err("Ed25519 identity keys are not supported by DTLS!".to_string());
+        }
+        let certificate = if config.certificate_chain.is_empty() {
+            self_signed_certificate(&private_key)?
+        } else {
+            config.certificate_chain[0].clone()
+        };
+        let cipher_list = dtls_cipher_list(config.cipher_suites.as_slice());
+        if cipher_list.is_empty() {
+            return Err("No cipher suite supported by DTLS!".to_string());
+        }
+        let address_index_res = Ssl::new_ex_index::<String>();
+        if address_index_res.is_err() {
+            return Err("Error creating DTLS context!".to_string());
+        }
+        let address_index = address_index_res.unwrap();
+        let context_res = SslContext::builder(SslMethod::dtls()).and_then(|mut builder| {
+            builder.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+            builder.set_cipher_list(cipher_list.as_str())?;
+            builder.set_private_key(&private_key)?;
+            builder.set_certificate(&certificate)?;
+            for chain_certificate in config.certificate_chain.iter().skip(1) {
+                builder.add_extra_chain_cert(chain_certificate.clone())?;
+            }
+            builder.check_private_key()?;
+            // Both endpoints send their certificate, which is verified
+            // once the handshake finished, like `PublicKey` packages
+            builder.set_verify_callback(
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+                |_, _| true
+            );
+            let mut options = SslOptions::NO_QUERY_MTU;
+            if config.cookie_exchange {
+                options |= SslOptions::COOKIE_EXCHANGE;
+                let generate_jar = cookie_jar.clone();
+                builder.set_cookie_generate_cb(move |ssl, buf| {
+                    let address = ssl.ex_data(address_index).cloned().unwrap_or(String::new());
+                    match generate_jar.read().unwrap().generate(&address, 0) {
+                        Ok(cookie) if cookie.len() <= buf.len() => {
+                            buf[..cookie.len()].copy_from_slice(cookie.as_slice());
+                            Ok(cookie.len())
+                        },
+                        _ => Err(ErrorStack::get())
+                    }
+                });
+                builder.set_cookie_verify_cb(move |ssl, cookie| {
+                    let address = ssl.ex_data(address_index).cloned().unwrap_or(String::new());
+                    cookie_jar.read().unwrap().verify(&address, 0, cookie)
+                });
+            }
+            builder.set_options(options);
+            Ok(builder.build())
+        });
+        if context_res.is_err() {
+            return Err("Error creating DTLS context!".to_string());
+        }
+        Ok(
+            Self {
+                context: context_res.unwrap(),
+                address_index: address_index
+            }
+        )
+    }
+}
+
+impl DtlsSession {
+    /**
+     * Starts a new DTLS session with a remote address. The accepting  
+     * endpoint passes the first datagrams it received from the address,  
+     * only the progress made with the last one is returned.
+     */
+    pub fn new(dtls: &DtlsContext, address: &String, initiator: bool, datagrams: Vec<Vec<u8>>) -> Result<(Self, DtlsProgress), Error> {
+        let channel = DtlsChannel {
+            incoming: VecDeque::new(),
+            outgoing: Vec::new()
+        };
+        let stream_res = Ssl::new(&dtls.context).and_then(|mut ssl| {
+            ssl.set_mtu(DTLS_MTU)?;
+            ssl.set_ex_data(dtls.address_index, address.clone());
+            if initiator {
+                ssl.set_connect_state();
+            } else {
+                ssl.set_accept_state();
+            }
+            SslStream::new(ssl, channel)
+        });
+        if stream_res.is_err() {
+            return Err("Error creating DTLS session!".to_string());
+        }
+        let session = Self {
+            address: address.clone(),
+            initiator: initiator,
+            created: Instant::now(),
+            stream: RwLock::new(stream_res.unwrap()),
+            established: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            pending: RwLock::new(Vec::new()),
+            peer: RwLock::new(None)
+        };
+        let mut datagrams = datagrams.into_iter();
+        let mut progress = session.process(datagrams.next())?;
+        for datagram in datagrams {
+            progress = session.process(Some(datagram))?;
+        }
+        Ok(
+            (session, progress)
+        )
+    }
+
+    /**
+     * Passes a received datagram to the session, or just lets openssl resend  
+     * handshake messages whose timer expired if there is none.
+     */
+    pub fn process(&self, datagram: Option<Vec<u8>>) -> Result<DtlsProgress, Error> {
+        let mut stream = self.stream.write().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return Err("DTLS session is closed!".to_string());
+        }
+        stream.get_mut().incoming.extend(datagram);
+        let mut established = false;
+        if !self.established.load(Ordering::Relaxed) {
+            match stream.do_handshake() {
+                Ok(()) => {
+                    self.established.store(true, Ordering::Relaxed);
+                    established = true;
+                },
+                Err(ref error) if error.code() == ErrorCode::WANT_READ => {},
+                Err(_) => {
+                    self.closed.store(true, Ordering::Relaxed);
+                    return Err("DTLS handshake failed!".to_string());
+                }
+            }
+        }
+        let records_res = if self.established.load(Ordering::Relaxed) {
+            read_records(&mut stream)
+        } else {
+            Ok(Vec::new())
+        };
+        if records_res.is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        let datagrams = mem::take(&mut stream.get_mut().outgoing);
+        Ok(
+            DtlsProgress {
+                established: established,
+                records: records_res?,
+                datagrams: datagrams
+            }
+        )
+    }
+
+    /**
+     * Writes an encoded package to the session and returns the datagrams to send.  
+     * Until the handshake is finished, the package is kept and written afterwards.
+     */
+    pub fn write(&self, data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let mut stream = self.stream.write().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return Err("DTLS session is closed!".to_string());
+        }
+        if !self.established.load(Ordering::Relaxed) {
+            self.pending.write().unwrap().push(data.to_vec());
+            return Ok(
+                Vec::new()
+            );
+        }
+        let write_res = stream.ssl_write(data);
+        let datagrams = mem::take(&mut stream.get_mut().outgoing);
+        if write_res.is_err() {
+            return Err("Error writing to DTLS session!".to_string());
+        }
+        Ok(
+            datagrams
+        )
+    }
+
+    /**
+     * Closes the session and returns the datagrams notifying the remote endpoint.
+     */
+    pub fn shutdown(&self) -> Vec<Vec<u8>> {
+        let mut stream = self.stream.write().unwrap();
+        if self.closed.swap(true, Ordering::Relaxed) || !self.established.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+        stream.shutdown().unwrap_or(ShutdownResult::Sent);
+        mem::take(&mut stream.get_mut().outgoing)
+    }
+
+    /**
+     * Internal function collecting the certificate chain (binary DER, leaf first),  
+     * the public key and the negotiated cipher suite of the remote endpoint.
+     */
+    fn remote_endpoint(&self) -> Result<(Vec<Vec<u8>>, Option<CipherSuite>), Error> {
+        let stream = self.stream.read().unwrap();
+        if !self.established.load(Ordering::Relaxed) {
+            return Err("DTLS session is not established!".to_string());
+        }
+        let ssl = stream.ssl();
+        let mut chain = Vec::new();
+        if let Some(leaf) = ssl.peer_certificate() {
+            let leaf_der = leaf.to_der().unwrap_or(Vec::new());
+            chain.push(leaf_der.clone());
+            // Servers see the chain of a client without its leaf certificate
+            if let Some(peer_chain) = ssl.peer_cert_chain() {
+                for certificate in peer_chain.iter() {
+                    let der = certificate.to_der().unwrap_or(Vec::new());
+                    if der != leaf_der {
+                        chain.push(der);
+                    }
+                }
+            }
+        }
+        let cipher_suite = ssl.current_cipher().map(|cipher| {
+            if cipher.name().contains("CHACHA20") {
+                CipherSuite::ChaCha20Poly1305
+            } else {
+                CipherSuite::Aes256Gcm
+            }
+        });
+        Ok(
+            (chain, cipher_suite)
+        )
+    }
+}
+
+/**
+ * Internal function reading all application data records available.
+ */
+fn read_records(ssl_stream: &mut SslStream<DtlsChannel>) -> Result<Vec<Vec<u8>>, Error> {
+    let mut records = Vec::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        match ssl_stream.ssl_read(buf.as_mut_slice()) {
+            Ok(len) => records.push(buf[..len].to_vec()),
+            Err(ref error) if error.code() == ErrorCode::WANT_READ => break,
+            Err(ref error) if error.code() == ErrorCode::ZERO_RETURN => {
+                return Err("DTLS session closed by the remote endpoint!".to_string());
+            },
+            Err(_) => return Err("DTLS session failed!".to_string())
+        }
+    }
+    Ok(
+        records
+    )
+}
+
+impl Endpoint {
+    /**
+     * Internal function starting a DTLS session with the address of  
+     * a new connection we initiate, unless there is one already.
+     */
+    pub(crate) fn open_dtls_session(&self, conn: &ConnectionArc) -> Result<(), Error> {
+        let address = dtls_address(&conn.address);
+        let existing = self.dtls_sessions.read().unwrap().get(&address).cloned();
+        if let Some(existing) = existing {
+            // The remote endpoint is verified already
+            if existing.peer.read().unwrap().is_some() && !self.apply_dtls_peer(conn) {
+                return Err("Remote endpoint rejected!".to_string());
+            }
+            return Ok(());
+        }
+        let (session, progress) = DtlsSession::new(self.dtls.as_ref().unwrap(), &address, true, Vec::new())?;
+        self.dtls_sessions.write().unwrap().insert(address.clone(), Arc::new(session));
+        self.send_datagrams(progress.datagrams, &address);
+        Ok(())
+    }
+
+    /**
+     * Internal function writing an encoded package to the DTLS session  
+     * with an address.
+     */
+    pub(crate) fn dtls_send(&self, data: &[u8], address: &String) -> Result<usize, Error> {
+        let address = dtls_address(address);
+        let session = self.dtls_sessions.read().unwrap().get(&address).cloned();
+        if session.is_none() {
+            return Err(format!("No DTLS session with {} !", &address));
+        }
+        let datagrams = session.unwrap().write(data)?;
+        self.send_datagrams(datagrams, &address);
+        Ok(
+            data.len()
+        )
+    }
+
+    /**
+     * Internal function receiving a datagram in `Transport::Dtls` mode and returning  
+     * the packages it contained. Datagrams from unknown addresses start a new session  
+     * once they passed `check_dtls_cookie`.
+     */
+    pub(crate) fn receive_dtls(&self) -> Vec<(Package, String, usize)> {
+        let mut data: Vec<u8> = vec![0; self.config.buffer_size as usize];
+        let recv_res = self.socket.recv_from(data.as_mut_slice());
+        if recv_res.is_err() {
+            return Vec::new();
+        }
+        let (real_size, addr) = recv_res.unwrap();
+        data.truncate(real_size);
+        let address = addr.to_string();
+        let session = self.dtls_sessions.read().unwrap().get(&address).cloned();
+        let progress_res = match session {
+            Some(session) => session.process(Some(data)).map(|progress| (session, progress)),
+            None => {
+                let datagrams = self.check_dtls_cookie(&address, data);
+                if datagrams.is_none() {
+                    return Vec::new();
+                }
+                DtlsSession::new(self.dtls.as_ref().unwrap(), &address, false, datagrams.unwrap())
+                    .map(|(session, progress)| {
+                        let session = Arc::new(session);
+                        self.dtls_sessions.write().unwrap().insert(address.clone(), session.clone());
+                        (session, progress)
+                    })
+            }
+        };
+        if progress_res.is_err() {
+            self.close_dtls_session(&address);
+            return Vec::new();
+        }
+        let (session, progress) = progress_res.unwrap();
+        self.handle_dtls_progress(&session, progress)
+    }
+
+    /**
+     * Internal function checking the cookie of a datagram from an unknown address  
+     * before any state is kept for it, like `DTLSv1_listen`. Without a valid cookie,  
+     * a HelloVerifyRequest is sent and None returned. Otherwise the datagrams starting  
+     * the session are returned, first the ClientHello sent before the cookie,  
+     * so openssl expects the one carrying it next, as if it had answered the first.
+     */
+    fn check_dtls_cookie(&self, address: &String, datagram: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        if !self.config.cookie_exchange {
+            return Some(vec![datagram]);
+        }
+        let client_hello = ClientHello::parse(datagram.as_slice())?;
+        let cookie_jar = self.cookie_jar.read().unwrap();
+        if !cookie_jar.verify(address, 0, client_hello.cookie()) {
+            if let Ok(cookie) = cookie_jar.generate(address, 0) {
+                let request = client_hello.hello_verify_request(cookie.as_slice());
+                self.send_raw(request.as_slice(), address).unwrap_or(0);
+            }
+            return None;
+        }
+        Some(vec![client_hello.initial(), datagram])
+    }
+
+    /**
+     * Internal function letting all DTLS sessions resend handshake messages  
+     * whose timer expired, and dropping sessions which did not finish their handshake  
+     * or are not used by any connection within `max_ack_attempts` times `ack_loop_time` ms.
+     */
+    pub(crate) fn poll_dtls_sessions(&self) {
+        let timeout = Duration::from_millis(self.config.ack_loop_time * self.config.max_ack_attempts as u64);
+        let sessions: Vec<Arc<DtlsSession>> = self.dtls_sessions.read().unwrap().values().cloned().collect();
+        for session in sessions {
+            let in_use = self.connection_list.read().unwrap().values()
+                .any(|connection| dtls_address(&connection.address) == session.address);
+            if session.created.elapsed() > timeout &&
+                (!in_use || !session.established.load(Ordering::Relaxed)) {
+                self.close_dtls_session(&session.address);
+                continue;
+            }
+            let progress_res = session.process(None);
+            if progress_res.is_err() {
+                self.close_dtls_session(&session.address);
+                continue;
+            }
+            for (package, addr, size) in self.handle_dtls_progress(&session, progress_res.unwrap()) {
+                self.handle_package(addr, package, size);
+            }
+        }
+    }
+
+    /**
+     * Internal function closing the DTLS session with an address,  
+     * disconnecting all connections using it.
+     */
+    pub(crate) fn close_dtls_session(&self, address: &String) {
+        let session = self.dtls_sessions.write().unwrap().remove(address);
+        if session.is_none() {
+            return;
+        }
+        self.send_datagrams(session.unwrap().shutdown(), address);
+        let connections: Vec<ConnectionArc> = self.connection_list.read().unwrap().values()
+            .filter(|connection| dtls_address(&connection.address) == *address)
+            .cloned()
+            .collect();
+        for connection in connections {
+            *connection.state.write().unwrap() = ConnectionState::Disconnected;
+            self.connection_list.write().unwrap().remove(&connection.id);
+        }
+    }
+
+    /**
+     * Internal function marking a connection as secure and storing the certificate  
+     * and public key of the remote endpoint of its established DTLS session.  
+     * Returns false if the connection must be rejected.
+     */
+    pub(crate) fn apply_dtls_peer(&self, conn: &ConnectionArc) -> bool {
+        let address = dtls_address(&conn.address);
+        let session = self.dtls_sessions.read().unwrap().get(&address).cloned();
+        if session.is_none() {
+            return false;
+        }
+        let session = session.unwrap();
+        let peer = session.peer.read().unwrap();
+        if peer.is_none() {
+            return false;
+        }
+        let peer = peer.as_ref().unwrap();
+        if let Some(ref public_key) = peer.public_key {
+            if !self.check_host_key(conn, public_key.as_slice()) ||
+                conn.set_public_key(public_key.clone()).is_err() {
+                return false;
+            }
+        }
+        *conn.certificate.write().unwrap() = peer.certificate.clone();
+        *conn.cipher_suite.write().unwrap() = peer.cipher_suite;
+        conn.address_validated.store(true, Ordering::Relaxed);
+        *conn.crypt_state.write().unwrap() = CryptState::Symm;
+        true
+    }
+
+    /**
+     * Internal function sending the datagrams of a DTLS session. Once its handshake  
+     * finished, the remote endpoint is verified, our connections to it are secure  
+     * and the packages sent in the meantime are written. Returns the received packages.
+     */
+    fn handle_dtls_progress(&self, session: &Arc<DtlsSession>, progress: DtlsProgress) -> Vec<(Package, String, usize)> {
+        self.send_datagrams(progress.datagrams, &session.address);
+        if progress.established {
+            if self.verify_dtls_peer(session).is_err() {
+                self.close_dtls_session(&session.address);
+                return Vec::new();
+            }
+            let connections: Vec<ConnectionArc> = self.connection_list.read().unwrap().values()
+                .filter(|connection| dtls_address(&connection.address) == session.address)
+                .cloned()
+                .collect();
+            for connection in connections {
+                if !self.apply_dtls_peer(&connection) {
+                    self.disconnect(&connection.id);
+                }
+            }
+            let pending = mem::take(&mut *session.pending.write().unwrap());
+            for data in pending {
+                self.dtls_send(data.as_slice(), &session.address).unwrap_or(0);
+            }
+        }
+        let mut packages = Vec::new();
+        for record in progress.records {
+            let size = record.len();
+            if let Ok(package) = Package::try_from(record) {
+                packages.push((package, session.address.clone(), size));
+            }
+        }
+        packages
+    }
+
+    /**
+     * Internal function verifying the certificate chain of the remote endpoint  
+     * of a DTLS session against the trusted CAs, if configured.
+     */
+    fn verify_dtls_peer(&self, session: &DtlsSession) -> Result<(), Error> {
+        let (chain, cipher_suite) = session.remote_endpoint()?;
+        let certificate = match self.config.trusted_ca {
+            Some(ref store) => Some(verify_chain(store, chain.as_slice(), self.config.peer_name.as_ref())?),
+            None => chain.first().and_then(|der| X509::from_der(der.as_slice()).ok())
+        };
+        let public_key = certificate.as_ref()
+            .and_then(|certificate| certificate.public_key().ok())
+            .and_then(|public_key| public_key.public_key_to_der().ok());
+        *session.peer.write().unwrap() = Some(DtlsPeer {
+            certificate: certificate,
+            public_key: public_key,
+            cipher_suite: cipher_suite
+        });
+        Ok(())
+    }
+
+    /**
+     * Internal function sending datagrams to an address.
+     */
+    fn send_datagrams(&self, datagrams: Vec<Vec<u8>>, address: &String) {
+        for datagram in datagrams {
+            self.send_raw(datagram.as_slice(), address).unwrap_or(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use super::*;
+
+    fn wait<F: Fn() -> bool>(condition: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn config() -> EndpointConfig {
+        let mut config = EndpointConfig::new(&"127.0.0.1:0".to_string());
+        config.transport = Transport::Dtls;
+        config
+    }
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        socket
+    }
+
+    fn is_hello_verify_request(datagram: &[u8]) -> bool {
+        datagram.len() > 27 && datagram[0] == 22 && datagram[13] == 3
+    }
+
+    fn request_cookie(socket: &UdpSocket, address: &String) -> Vec<u8> {
+        socket.send_to(client_hello(0, 0, &[]).as_slice(), address).unwrap();
+        let mut buffer = vec![0; 4096];
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        assert!(is_hello_verify_request(&buffer[..len]));
+        let cookie_len = buffer[26] as usize;
+        buffer[27..27 + cookie_len].to_vec()
+    }
+
+    fn client_hello(record_seq: u8, message_seq: u8, cookie: &[u8]) -> Vec<u8> {
+        let mut body = vec![0xfe, 0xfd];
+        body.extend_from_slice(&[0x42; 32]);
+        body.push(0);
+        body.push(cookie.len() as u8);
+        body.extend_from_slice(cookie);
+        body.extend_from_slice(&[0, 2, 0xc0, 0x30, 1, 0]);
+        let mut record = vec![22, 0xfe, 0xfd, 0, 0, 0, 0, 0, 0, 0, record_seq];
+        record.extend_from_slice(&((DTLS_HANDSHAKE_HEADER_LEN + body.len()) as u16).to_be_bytes());
+        record.push(1);
+        record.extend_from_slice(&write_u24(body.len()));
+        record.extend_from_slice(&[0, message_seq, 0, 0, 0]);
+        record.extend_from_slice(&write_u24(body.len()));
+        record.append(&mut body);
+        record
+    }
+
+    #[test]
+    fn parses_client_hello_cookies() {
+        let hello = ClientHello::parse(client_hello(1, 1, &[7, 8, 9]).as_slice()).unwrap();
+        assert_eq!(hello.cookie(), &[7, 8, 9]);
+        assert!(ClientHello::parse(client_hello(0, 0, &[]).as_slice()).unwrap().cookie().is_empty());
+        let datagram = client_hello(1, 1, &[7, 8, 9]);
+        assert!(ClientHello::parse(&datagram[..datagram.len() - 1]).is_none());
+        assert!(ClientHello::parse(&[22, 0xfe, 0xfd]).is_none());
+    }
+
+    #[test]
+    fn rebuilds_initial_client_hello() {
+        let hello = ClientHello::parse(client_hello(1, 1, &[7, 8, 9]).as_slice()).unwrap();
+        assert_eq!(hello.initial(), client_hello(0, 0, &[]));
+    }
+
+    #[test]
+    fn builds_hello_verify_request() {
+        let hello = ClientHello::parse(client_hello(5, 0, &[]).as_slice()).unwrap();
+        let request = hello.hello_verify_request(&[1, 2, 3, 4]);
+        assert_eq!(&request[..13], &[22, 0xfe, 0xff, 0, 0, 0, 0, 0, 0, 0, 5, 0, 19]);
+        assert_eq!(&request[13..25], &[3, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(&request[25..], &[0xfe, 0xff, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_padding_and_header_protection() {
+        let mut protected_config = config();
+        protected_config.header_protection = true;
+        assert!(Endpoint::new(protected_config).is_err());
+        let mut padded_config = config();
+        padded_config.padding_policy = PaddingPolicy::Mtu(256);
+        assert!(Endpoint::new(padded_config).is_err());
+    }
+
+    #[test]
+    fn handshake_with_stateless_cookie() {
+        let server = Endpoint::new(config()).unwrap();
+        let client = Endpoint::new(config()).unwrap();
+        let server_address = server.socket.local_addr().unwrap();
+        // Relays the datagrams between both endpoints and notes the HelloVerifyRequest
+        let proxy = socket();
+        let relay = socket();
+        let proxy_address = proxy.local_addr().unwrap().to_string();
+        let running = Arc::new(AtomicBool::new(true));
+        let verify_requested = Arc::new(AtomicBool::new(false));
+        let relay_thread = {
+            let running = running.clone();
+            let verify_requested = verify_requested.clone();
+            thread::spawn(move || {
+                let mut client_address = None;
+                let mut buffer = vec![0; 4096];
+                while running.load(Ordering::Relaxed) {
+                    if let Ok((len, address)) = proxy.recv_from(&mut buffer) {
+                        client_address = Some(address);
+                        relay.send_to(&buffer[..len], server_address).unwrap_or(0);
+                    }
+                    if let Ok((len, _)) = relay.recv_from(&mut buffer) {
+                        if is_hello_verify_request(&buffer[..len]) {
+                            verify_requested.store(true, Ordering::Relaxed);
+                        }
+                        if let Some(address) = client_address {
+                            proxy.send_to(&buffer[..len], address).unwrap_or(0);
+                        }
+                    }
+                }
+            })
+        };
+        let conn = client.connect(&proxy_address).unwrap();
+        assert!(wait(|| *conn.crypt_state.read().unwrap() == CryptState::Symm));
+        assert!(verify_requested.load(Ordering::Relaxed));
+        assert!(wait(|| server.dtls_sessions.read().unwrap().len() == 1));
+        running.store(false, Ordering::Relaxed);
+        relay_thread.join().unwrap();
+        client.stop();
+        server.stop();
+    }
+
+    #[test]
+    fn rejects_forged_cookies() {
+        let server = Endpoint::new(config()).unwrap();
+        let server_address = server.socket.local_addr().unwrap().to_string();
+        let sender = socket();
+        let mut cookie = request_cookie(&sender, &server_address);
+        let last = cookie.len() - 1;
+        cookie[last] ^= 1;
+        sender.send_to(client_hello(1, 1, cookie.as_slice()).as_slice(), &server_address).unwrap();
+        let mut buffer = vec![0; 4096];
+        let (len, _) = sender.recv_from(&mut buffer).unwrap();
+        assert!(is_hello_verify_request(&buffer[..len]));
+        // The cookie of another address is not valid either
+        let cookie = request_cookie(&sender, &server_address);
+        let other = socket();
+        other.send_to(client_hello(1, 1, cookie.as_slice()).as_slice(), &server_address).unwrap();
+        let (len, _) = other.recv_from(&mut buffer).unwrap();
+        assert!(is_hello_verify_request(&buffer[..len]));
+        thread::sleep(Duration::from_millis(100));
+        assert!(server.dtls_sessions.read().unwrap().is_empty());
+        server.stop();
+    }
+}
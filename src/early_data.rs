@@ -0,0 +1,298 @@
+use std::convert::*;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec::*;
+
+use rmps::*;
+
+use crate::prelude::*;
+
+/**
+ * Associated data binding sealed early data to its purpose
+ */
+const EARLY_DATA_AAD: &[u8] = b"udps early data";
+
+/**
+ * Application data sent with the `Connect` package, before the handshake finished.  
+ * It is sealed with a key derived from a resumption secret or the pre-shared key  
+ * and the nonce of the initiating endpoint, if one of them is available.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct EarlyData {
+    /**
+     * Time the data was sent as ms since the UNIX epoch
+     */
+    pub sent_at: u64,
+    /**
+     * Application data
+     */
+    pub data: Vec<u8>,
+}
+
+impl EarlyData {
+    /**
+     * Creates early data sent right now.
+     */
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            sent_at: unix_time_millis(),
+            data: data
+        }
+    }
+
+    /**
+     * Derives the key sealing early data from a resumption secret  
+     * or pre-shared key and the nonce of the initiating endpoint.
+     */
+    pub fn derive_key(secret: &Secret, initiator_nonce: &[u8]) -> Result<Secret, String> {
+        let mut info = b"udps early data key".to_vec();
+        info.extend_from_slice(initiator_nonce);
+        hmac_sha256(secret.as_bytes(), info.as_slice()).map(Secret::new)
+    }
+
+    /**
+     * Encodes the early data of a connection, sealed with the cipher suite if a key is given.
+     */
+    pub fn seal(&self, key: Option<&Secret>, cipher_suite: CipherSuite, connection_id: u32) -> Result<Vec<u8>, Error> {
+        let encode_res = to_vec(self);
+        if encode_res.is_err() {
+            return Err("Unknown error encoding MessagePack early data!".to_string());
+        }
+        let encoded = encode_res.unwrap();
+        match key {
+            Some(key) => cipher_suite.cipher().seal(
+                key.as_bytes(),
+                Self::associated_data(connection_id).as_slice(),
+                encoded.as_slice()
+            ),
+            None => Ok(encoded)
+        }
+    }
+
+    /**
+     * Opens and decodes the early data of a connection.  
+     * Fails if it was not sealed with the key, or sealed without one given.
+     */
+    pub fn open(key: Option<&Secret>, cipher_suite: CipherSuite, connection_id: u32, sealed: &[u8]) -> Result<Self, Error> {
+        let data = match key {
+            Some(key) => cipher_suite.cipher().open(
+                key.as_bytes(),
+                Self::associated_data(connection_id).as_slice(),
+                sealed
+            )?,
+            None => sealed.to_vec()
+        };
+        let early_data_res = from_slice::<Self>(data.as_slice());
+        if early_data_res.is_err() {
+            return Err("Unknown error decoding MessagePack early data!".to_string());
+        }
+        Ok(
+            early_data_res.unwrap()
+        )
+    }
+
+    /**
+     * Returns true if the data was sent no longer than `window` ago.  
+     * Clocks of both endpoints may differ by as much in the other direction.
+     */
+    pub fn is_fresh(&self, window: Duration) -> bool {
+        let now = unix_time_millis();
+        let window = window.as_millis() as u64;
+        // `sent_at` is chosen by the remote endpoint, it must not overflow
+        self.sent_at.saturating_add(window) >= now && self.sent_at <= now.saturating_add(window)
+    }
+
+    fn associated_data(connection_id: u32) -> Vec<u8> {
+        let mut aad = EARLY_DATA_AAD.to_vec();
+        aad.extend_from_slice(&conv_u32_to_bytes(&connection_id));
+        aad
+    }
+}
+
+/**
+ * Remembers the nonces of the `Connect` packages whose early data was accepted,  
+ * so early data replayed within `early_data_window` is accepted only once.  
+ * Older early data is rejected for its age already.
+ */
+pub struct EarlyDataFilter {
+    /**
+     * Nonces by the time they were seen
+     */
+    seen: HashMap<Vec<u8>, Instant>,
+}
+
+impl EarlyDataFilter {
+    /**
+     * Creates an empty filter.
+     */
+    pub fn new() -> Self {
+        Self {
+            seen: HashMap::new()
+        }
+    }
+
+    /**
+     * Returns true if the nonce was not seen within the window before,  
+     * and remembers it. Nonces older than the window are forgotten.
+     */
+    pub fn check(&mut self, nonce: &[u8], window: Duration) -> bool {
+        // Early data may be up to a window old and sent from a clock a window ahead
+        self.seen.retain(|_, seen_at| seen_at.elapsed() < window * 2);
+        if self.seen.contains_key(nonce) {
+            return false;
+        }
+        self.seen.insert(nonce.to_vec(), Instant::now());
+        true
+    }
+}
+
+impl Default for EarlyDataFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Endpoint {
+    /**
+     * Internal function returning the key sealing the early data of a connection  
+     * we initiate, derived from our resumption ticket or the pre-shared key.
+     */
+    pub(crate) fn early_data_key(&self, conn: &ConnectionArc, initiator_nonce: &[u8]) -> Result<Option<Secret>, Error> {
+        if let Some(ref ticket) = *conn.ticket.read().unwrap() {
+            return EarlyData::derive_key(&ticket.resumption_secret, initiator_nonce).map(Some);
+        }
+        if self.config.key_exchange == KeyExchange::Psk {
+            return EarlyData::derive_key(self.config.psk.as_ref().unwrap(), initiator_nonce).map(Some);
+        }
+        Ok(None)
+    }
+
+    /**
+     * Internal function checking the early data of a `Connect` package from an  
+     * unknown connection, and queueing it in the new connection if it is accepted.  
+     * It must be sealed with the key derived from a resumption ticket we can open  
+     * or our pre-shared key, unless plain data is allowed, sent no longer than  
+     * `early_data_window` ms ago and not replayed.
+     */
+    pub(crate) fn accept_early_data(&self, conn: &ConnectionArc, package: &Package) {
+        if self.config.early_data_window.is_none() {
+            return;
+        }
+        let window = Duration::from_millis(self.config.early_data_window.unwrap());
+        let message_res = ConnectMessage::try_from(package.data.clone());
+        if message_res.is_err() {
+            return;
+        }
+        let message = message_res.unwrap();
+        let initiator_nonce = message.nonce.clone().unwrap_or(Vec::new());
+        let preferred_suite = message.cipher_suites.first().cloned();
+        if message.early_data.is_none() || initiator_nonce.len() != KEY_LEN || preferred_suite.is_none() {
+            return;
+        }
+        // Early data resuming a session is sealed with the suite negotiated for the ticket,
+        // otherwise with the suite preferred by the initiating endpoint
+        let resumes = self.config.key_exchange == KeyExchange::Rsa || self.config.key_exchange == KeyExchange::Ecdh;
+        let key_res = match message.ticket {
+            Some(ref sealed_ticket) if resumes => {
+                ResumptionTicket::open(self.ticket_key.as_bytes(), sealed_ticket.as_slice())
+                    .and_then(|ticket| {
+                        if ticket.is_expired() {
+                            return Err("Resumption ticket expired!".to_string());
+                        }
+                        let resumption_secret = Secret::new(ticket.resumption_secret.clone());
+                        EarlyData::derive_key(&resumption_secret, &initiator_nonce)
+                            .map(|key| (Some(key), ticket.cipher_suite))
+                    })
+            },
+            _ if self.config.key_exchange == KeyExchange::Psk &&
+                message.psk_identity.as_ref() == self.config.psk_identity.as_ref() => {
+                EarlyData::derive_key(self.config.psk.as_ref().unwrap(), &initiator_nonce)
+                    .map(|key| (Some(key), preferred_suite.unwrap()))
+            },
+            _ if self.config.encryption_policy == EncryptionPolicy::AllowPlaintext => {
+                Ok((None, preferred_suite.unwrap()))
+            },
+            _ => Err("No key for early data!".to_string())
+        };
+        if key_res.is_err() {
+            return;
+        }
+        let (key, cipher_suite) = key_res.unwrap();
+        if !self.config.cipher_suites.contains(&cipher_suite) {
+            return;
+        }
+        let early_data_res = EarlyData::open(
+            key.as_ref(),
+            cipher_suite,
+            conn.id,
+            message.early_data.unwrap().as_slice()
+        );
+        if early_data_res.is_err() {
+            conn.dropped_packages.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let early_data = early_data_res.unwrap();
+        if !early_data.is_fresh(window) ||
+            !self.early_data_filter.write().unwrap().check(initiator_nonce.as_slice(), window) {
+            conn.dropped_packages.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut early_package = Package::new_default();
+        early_package.header.connection_id = conn.id;
+        early_package.data = early_data.data;
+        conn.push_package(early_package);
+        conn.early_data_accepted.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Returns the current time as ms since the UNIX epoch.
+ */
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_at(sent_at: u64) -> EarlyData {
+        EarlyData {
+            sent_at: sent_at,
+            data: Vec::new()
+        }
+    }
+
+    #[test]
+    fn accepts_data_within_the_window() {
+        let window = Duration::from_millis(10000);
+        let now = unix_time_millis();
+        assert!(EarlyData::new(Vec::new()).is_fresh(window));
+        assert!(sent_at(now - 5000).is_fresh(window));
+        assert!(sent_at(now + 5000).is_fresh(window));
+        assert!(!sent_at(now - 20000).is_fresh(window));
+        assert!(!sent_at(now + 20000).is_fresh(window));
+    }
+
+    #[test]
+    fn rejects_extreme_timestamps() {
+        let window = Duration::from_millis(10000);
+        assert!(!sent_at(u64::max_value()).is_fresh(window));
+        assert!(!sent_at(0).is_fresh(window));
+        // An unlimited window accepts anything, without overflowing
+        assert!(sent_at(u64::max_value()).is_fresh(Duration::from_millis(u64::max_value())));
+    }
+
+    #[test]
+    fn filters_replayed_nonces() {
+        let mut filter = EarlyDataFilter::default();
+        let window = Duration::from_millis(10000);
+        assert!(filter.check(&[1; KEY_LEN], window));
+        assert!(!filter.check(&[1; KEY_LEN], window));
+        assert!(filter.check(&[2; KEY_LEN], window));
+    }
+}
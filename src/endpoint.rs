@@ -20,9 +20,13 @@ use std::time::{
     Duration
 };
 
+use std::fs;
+
 use rand::prelude::*;
 use openssl::rsa::*;
 use openssl::pkey::*;
+use openssl::x509::X509;
+use openssl::x509::store::X509Store;
 use crate::prelude::*;
 
 /**
@@ -34,6 +38,23 @@ pub type Error = String;
  */
 pub type EndpointArc = Arc<Endpoint>;
 
+/**
+ * Whether application data may be sent and received without encryption
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EncryptionPolicy {
+    /**
+     * `Data` packages are only sent and accepted once the connection is secure  
+     * (`CryptState::Symm`), remote endpoints sending plain ones are disconnected
+     */
+    RequireEncryption = 0,
+    /**
+     * `Data` packages are sent in clear until the connection is secure,  
+     * and plain ones are accepted at any time
+     */
+    AllowPlaintext,
+}
+
 /**
  * Endpoint configuration
  */
@@ -47,7 +68,7 @@ pub struct EndpointConfig {
      */
     pub buffer_size: u32,
     /**
-     * The amount of time (in ms) the socket will
+     * The amount of time (in ms) the socket will  
      * stop blocking after not receiving data
      */
     pub read_timeout: u64,
@@ -68,17 +89,145 @@ pub struct EndpointConfig {
      */
     pub max_package_backlog: u32,
     /**
-     * Private RSA key, used for transmitting the symmetric key
+     * Private RSA key, used for transmitting the symmetric key.  
+     * Not needed in `KeyExchange::Psk` mode
+     */
+    pub private_key: Option<Rsa<Private>>,
+    /**
+     * Private Ed25519 key, used instead of `private_key` as identity key  
+     * in `KeyExchange::Ecdh` mode. Much smaller and faster than RSA-2048
      */
-    pub private_key: Rsa<Private>,
+    pub ed25519_key: Option<PKey<Private>>,
     /**
      * Private AES key, used for encrypting outgoing messages
      */
-    pub secret_key: Vec<u8>
+    pub secret_key: Secret,
+    /**
+     * Supported cipher suites in order of preference
+     */
+    pub cipher_suites: Vec<CipherSuite>,
+    /**
+     * Key exchange used to establish the secret key,  
+     * must be the same on both endpoints
+     */
+    pub key_exchange: KeyExchange,
+    /**
+     * File to record the public keys of the endpoints we connect to in,  
+     * no checks are done if `None`
+     */
+    pub known_hosts_file: Option<String>,
+    /**
+     * What to do if a public key differs from the recorded one
+     */
+    pub host_key_policy: HostKeyPolicy,
+    /**
+     * Certificate chain (leaf first) sent instead of the bare public key,  
+     * the leaf certificate must belong to `private_key`
+     */
+    pub certificate_chain: Vec<X509>,
+    /**
+     * Trusted CA certificates. If set, remote endpoints must send  
+     * a certificate chain which can be verified with them
+     */
+    pub trusted_ca: Option<X509Store>,
+    /**
+     * Name the certificates of remote endpoints must be issued for  
+     * (host name, IP address or subject common name), not checked if `None`
+     */
+    pub peer_name: Option<String>,
+    /**
+     * Pre-shared key for `KeyExchange::Psk` mode
+     */
+    pub psk: Option<Secret>,
+    /**
+     * Identity of the pre-shared key, sent in clear with the `Connect` package
+     */
+    pub psk_identity: Option<String>,
+    /**
+     * Static X25519 key for `KeyExchange::Noise` mode
+     */
+    pub noise_static_key: Option<PKey<Private>>,
+    /**
+     * Noise pattern used to connect in `KeyExchange::Noise` mode,  
+     * incoming connections may use either pattern
+     */
+    pub noise_pattern: NoisePattern,
+    /**
+     * Raw static key of the endpoint we connect to, required for `NoisePattern::IK`
+     */
+    pub noise_remote_key: Option<Vec<u8>>,
+    /**
+     * Number of packages after which the secret key is renewed, never if `None`
+     */
+    pub rekey_after_packages: Option<u64>,
+    /**
+     * Number of bytes after which the secret key is renewed, never if `None`
+     */
+    pub rekey_after_bytes: Option<u64>,
+    /**
+     * Time (in ms) after which the secret key is renewed, never if `None`
+     */
+    pub rekey_after_time: Option<u64>,
+    /**
+     * Time (in ms) packages sealed with the previous secret key  
+     * are still accepted after the secret key was renewed
+     */
+    pub rekey_overlap: u64,
+    /**
+     * If true, `Connect` packages from unknown connections are answered with a  
+     * stateless cookie, and a connection is only created once it is sent back
+     */
+    pub cookie_exchange: bool,
+    /**
+     * Interval (in ms) after which the secret used for cookies is renewed
+     */
+    pub cookie_rotation: u64,
+    /**
+     * Until the address of a remote endpoint is validated, at most this  
+     * multiple of the bytes received from it is sent back
+     */
+    pub amplification_factor: u64,
+    /**
+     * Key (32 bytes) shared with the backend minting connect tokens.  
+     * If set, connections are only accepted with a valid `ConnectToken`
+     */
+    pub connect_token_key: Option<Secret>,
+    /**
+     * Time (in ms) the resumption tickets issued to endpoints connecting  
+     * to us stay valid, none are issued if `None`
+     */
+    pub ticket_lifetime: Option<u64>,
+    /**
+     * Time (in ms) early data sent with `Connect` packages is accepted after it  
+     * was sent, replays within it are dropped. Early data is dropped if `None`
+     */
+    pub early_data_window: Option<u64>,
+    /**
+     * Whether application data may be sent and received without encryption
+     */
+    pub encryption_policy: EncryptionPolicy,
+    /**
+     * Transport carrying the packages, must be the same on both endpoints
+     */
+    pub transport: Transport,
+    /**
+     * Whether package headers are masked and datagrams randomly padded, so they  
+     * look uniformly random. Must be the same on both endpoints, only used with `Transport::Udps`
+     */
+    pub header_protection: bool,
+    /**
+     * Upper bound (in bytes) of the random padding appended to datagrams with `header_protection`
+     */
+    pub max_datagram_padding: usize,
+    /**
+     * How the payloads of sealed `Data` packages are padded to hide their length,  
+     * only used with `Transport::Udps`
+     */
+    pub padding_policy: PaddingPolicy
 }
 
 /**
- * Endpoint struct.
+ * Endpoint struct.  
  * Represents an endpoint.  
  * An endpoint binds to a single address and port - you can let the  
  * OS choose one for you by picking ":0". An endpoint can have multiple connections, retrieved  
@@ -114,12 +263,41 @@ pub struct Endpoint {
      * List of new connections
      */
     pub new_connection_list: RwLock<Vec<ConnectionArc>>,
+    /**
+     * Public keys of the endpoints we connected to,  
+     * if `known_hosts_file` is configured
+     */
+    pub known_hosts: RwLock<Option<KnownHosts>>,
+    /**
+     * Secrets for the cookies sent to unknown connections
+     */
+    pub cookie_jar: Arc<RwLock<CookieJar>>,
+    /**
+     * Key sealing the resumption tickets we issue
+     */
+    pub ticket_key: Secret,
+    /**
+     * Resumption tickets received from the endpoints we connected to, by address
+     */
+    pub tickets: RwLock<HashMap<String, Arc<SessionTicket>>>,
+    /**
+     * Nonces of the `Connect` packages whose early data we accepted recently
+     */
+    pub early_data_filter: RwLock<EarlyDataFilter>,
+    /**
+     * openssl context for `Transport::Dtls` mode
+     */
+    pub dtls: Option<DtlsContext>,
+    /**
+     * DTLS sessions by remote address (`Transport::Dtls` only)
+     */
+    pub dtls_sessions: RwLock<HashMap<String, Arc<DtlsSession>>>,
     /**
      * Thread handle for the thread receiving data in the background
      */
     pub receive_thread: RwLock<Option<JoinHandle<()>>>,
     /**
-     * Thread handle for the thread resending packages until
+     * Thread handle for the thread resending packages until  
      * they are received
      */
     pub ack_thread: RwLock<Option<JoinHandle<()>>>
@@ -127,10 +305,79 @@ pub struct Endpoint {
 
 impl EndpointConfig {
     /**
-     * ## Creates a new configuration with default values.
+     * ## Creates a new configuration with default values.  
+     * A new RSA key is generated every time, use  
+     * `from_pem_file` or `from_der_file` to keep the identity  
+     * of an endpoint across restarts.
      */
     pub fn new(address: &String) -> Self {
         let rsa = Rsa::generate(2048).unwrap();
+        Self::with_private_key(address, rsa)
+    }
+
+    /**
+     * ## Creates a new configuration with default values and the given private key.
+     */
+    pub fn with_private_key(address: &String, private_key: Rsa<Private>) -> Self {
+        Self::with_defaults(address, Some(private_key))
+    }
+
+    /**
+     * ## Creates a new configuration with a new Ed25519 identity key.  
+     * Ed25519 keys can only sign, so `KeyExchange::Ecdh` is used.
+     */
+    pub fn new_ed25519(address: &String) -> Self {
+        let ed25519_key = PKey::generate_ed25519().unwrap();
+        Self::with_ed25519_key(address, ed25519_key)
+    }
+
+    /**
+     * ## Creates a new configuration with the given Ed25519 identity key.  
+     * Ed25519 keys can only sign, so `KeyExchange::Ecdh` is used.
+     */
+    pub fn with_ed25519_key(address: &String, ed25519_key: PKey<Private>) -> Self {
+        let mut config = Self::with_defaults(address, None);
+        config.key_exchange = KeyExchange::Ecdh;
+        config.ed25519_key = Some(ed25519_key);
+        config
+    }
+
+    /**
+     * ## Creates a new configuration for `KeyExchange::Psk` mode.  
+     * No RSA key is generated, both endpoints must be  
+     * configured with the same identity and pre-shared key.
+     */
+    pub fn with_psk(address: &String, psk_identity: &String, psk: Vec<u8>) -> Self {
+        let mut config = Self::with_defaults(address, None);
+        config.key_exchange = KeyExchange::Psk;
+        config.psk_identity = Some(psk_identity.clone());
+        config.psk = Some(Secret::new(psk));
+        config
+    }
+
+    /**
+     * ## Creates a new configuration for `KeyExchange::Noise` mode with a new static key.  
+     * Remote endpoints connecting with `NoisePattern::IK` need our `noise_public_key`.
+     */
+    pub fn new_noise(address: &String) -> Self {
+        let noise_static_key = PKey::generate_x25519().unwrap();
+        Self::with_noise_key(address, noise_static_key)
+    }
+
+    /**
+     * ## Creates a new configuration for `KeyExchange::Noise` mode with the given static X25519 key.
+     */
+    pub fn with_noise_key(address: &String, noise_static_key: PKey<Private>) -> Self {
+        let mut config = Self::with_defaults(address, None);
+        config.key_exchange = KeyExchange::Noise;
+        config.noise_static_key = Some(noise_static_key);
+        config
+    }
+
+    /**
+     * Internal function creating a configuration with default values.
+     */
+    fn with_defaults(address: &String, private_key: Option<Rsa<Private>>) -> Self {
         Self {
             address: address.clone(),
             buffer_size: 8192,
@@ -139,17 +386,333 @@ impl EndpointConfig {
             ack_loop_time: 1000,
             max_ack_attempts: 20,
             max_package_backlog: 32,
-            private_key: rsa,
-            secret_key: generate_random_bytes(32)
+            private_key: private_key,
+            ed25519_key: None,
+            secret_key: Secret::random(32),
+            cipher_suites: CipherSuite::all(),
+            key_exchange: KeyExchange::Rsa,
+            known_hosts_file: None,
+            host_key_policy: HostKeyPolicy::Reject,
+            certificate_chain: Vec::new(),
+            trusted_ca: None,
+            peer_name: None,
+            psk: None,
+            psk_identity: None,
+            noise_static_key: None,
+            noise_pattern: NoisePattern::XX,
+            noise_remote_key: None,
+            rekey_after_packages: Some(1 << 24),
+            rekey_after_bytes: None,
+            rekey_after_time: None,
+            rekey_overlap: 10000,
+            cookie_exchange: true,
+            cookie_rotation: 60000,
+            amplification_factor: 3,
+            connect_token_key: None,
+            ticket_lifetime: Some(3600000),
+            early_data_window: Some(10000),
+            encryption_policy: EncryptionPolicy::RequireEncryption,
+            transport: Transport::Udps,
+            header_protection: false,
+            max_datagram_padding: 64,
+            padding_policy: PaddingPolicy::None
+        }
+    }
+
+    /**
+     * ## Creates a new configuration with the private key loaded from a PEM file.  
+     * RSA, Ed25519 and X25519 (`KeyExchange::Noise`) keys are supported.  
+     * Encrypted keys are decrypted with the passphrase.
+     */
+    pub fn from_pem_file(address: &String, path: &str, passphrase: Option<&[u8]>) -> Result<Self, Error> {
+        let pem_res = fs::read(path);
+        if pem_res.is_err() {
+            return Err(format!("Could not read private key file {} !", path));
+        }
+        let pem = pem_res.unwrap();
+        let pkey_res = match passphrase {
+            Some(passphrase) => PKey::private_key_from_pem_passphrase(pem.as_slice(), passphrase),
+            // Never let openssl prompt for a passphrase on the terminal
+            None => PKey::private_key_from_pem_callback(pem.as_slice(), |_| Ok(0))
+        };
+        if pkey_res.is_err() {
+            return Err("Error decoding PEM to private key!".to_string());
+        }
+        Self::with_identity_key(address, pkey_res.unwrap())
+    }
+
+    /**
+     * ## Creates a new configuration with the private key loaded from a DER file.  
+     * Plain RSA keys are PKCS#1, Ed25519, X25519 and encrypted keys are PKCS#8,  
+     * the latter decrypted with the passphrase.
+     */
+    pub fn from_der_file(address: &String, path: &str, passphrase: Option<&[u8]>) -> Result<Self, Error> {
+        let der_res = fs::read(path);
+        if der_res.is_err() {
+            return Err(format!("Could not read private key file {} !", path));
+        }
+        let der = der_res.unwrap();
+        let pkey_res = match passphrase {
+            Some(passphrase) => PKey::private_key_from_pkcs8_passphrase(der.as_slice(), passphrase),
+            None => Rsa::private_key_from_der(der.as_slice())
+                .and_then(PKey::from_rsa)
+                .or_else(|_| PKey::private_key_from_der(der.as_slice()))
+        };
+        if pkey_res.is_err() {
+            return Err("Error decoding DER to private key!".to_string());
+        }
+        Self::with_identity_key(address, pkey_res.unwrap())
+    }
+
+    /**
+     * Internal function creating a configuration for a loaded RSA, Ed25519 or X25519 key.
+     */
+    fn with_identity_key(address: &String, private_key: PKey<Private>) -> Result<Self, Error> {
+        if private_key.id() == Id::ED25519 {
+            return Ok(
+                Self::with_ed25519_key(address, private_key)
+            );
+        }
+        if private_key.id() == Id::X25519 {
+            return Ok(
+                Self::with_noise_key(address, private_key)
+            );
+        }
+        let rsa_res = private_key.rsa();
+        if rsa_res.is_err() {
+            return Err("Only RSA, Ed25519 and X25519 private keys are supported!".to_string());
+        }
+        Ok(
+            Self::with_private_key(address, rsa_res.unwrap())
+        )
+    }
+
+    /**
+     * Generates a new private key and saves it to a PEM file,  
+     * encrypted with AES-256-CBC if a passphrase is given.
+     */
+    pub fn generate_key_file(path: &str, passphrase: Option<&[u8]>) -> Result<Rsa<Private>, Error> {
+        let rsa_res = Rsa::generate(2048);
+        if rsa_res.is_err() {
+            return Err("Error generating private key!".to_string());
+        }
+        let rsa = rsa_res.unwrap();
+        let pem_res = match passphrase {
+            Some(passphrase) => rsa.private_key_to_pem_passphrase(
+                openssl::symm::Cipher::aes_256_cbc(),
+                passphrase
+            ),
+            None => rsa.private_key_to_pem()
+        };
+        save_private_key_pem(pem_res, path)?;
+        Ok(
+            rsa
+        )
+    }
+
+    /**
+     * Generates a new Ed25519 private key and saves it to a PEM file (PKCS#8),  
+     * encrypted with AES-256-CBC if a passphrase is given.
+     */
+    pub fn generate_ed25519_key_file(path: &str, passphrase: Option<&[u8]>) -> Result<PKey<Private>, Error> {
+        let pkey_res = PKey::generate_ed25519();
+        if pkey_res.is_err() {
+            return Err("Error generating private key!".to_string());
+        }
+        let pkey = pkey_res.unwrap();
+        save_private_key_pem(pkcs8_pem(&pkey, passphrase), path)?;
+        Ok(
+            pkey
+        )
+    }
+
+    /**
+     * Generates a new static X25519 key for `KeyExchange::Noise` mode and saves it  
+     * to a PEM file (PKCS#8), encrypted with AES-256-CBC if a passphrase is given.
+     */
+    pub fn generate_noise_key_file(path: &str, passphrase: Option<&[u8]>) -> Result<PKey<Private>, Error> {
+        let pkey_res = PKey::generate_x25519();
+        if pkey_res.is_err() {
+            return Err("Error generating private key!".to_string());
+        }
+        let pkey = pkey_res.unwrap();
+        save_private_key_pem(pkcs8_pem(&pkey, passphrase), path)?;
+        Ok(
+            pkey
+        )
+    }
+
+    /**
+     * Saves the private key to a PEM file,  
+     * encrypted with AES-256-CBC if a passphrase is given.
+     */
+    pub fn save_private_key(&self, path: &str, passphrase: Option<&[u8]>) -> Result<(), Error> {
+        if let Some(ref noise_static_key) = self.noise_static_key {
+            if self.key_exchange == KeyExchange::Noise {
+                return save_private_key_pem(pkcs8_pem(noise_static_key, passphrase), path);
+            }
+        }
+        if let Some(ref ed25519_key) = self.ed25519_key {
+            return save_private_key_pem(pkcs8_pem(ed25519_key, passphrase), path);
+        }
+        if self.private_key.is_none() {
+            return Err("No private key configured!".to_string());
+        }
+        let private_key = self.private_key.as_ref().unwrap();
+        let pem_res = match passphrase {
+            Some(passphrase) => private_key.private_key_to_pem_passphrase(
+                openssl::symm::Cipher::aes_256_cbc(),
+                passphrase
+            ),
+            None => private_key.private_key_to_pem()
+        };
+        save_private_key_pem(pem_res, path)
+    }
+
+    /**
+     * Loads the certificate chain (PEM, leaf first) to send to remote endpoints.  
+     * The leaf certificate must belong to our private key.
+     */
+    pub fn load_certificate_chain(&mut self, path: &str) -> Result<(), Error> {
+        let private_key = self.identity_key()?;
+        let certificates = load_certificates(path)?;
+        let matches_res = certificates[0].public_key()
+            .map(|public_key| public_key.public_eq(&private_key));
+        if matches_res.is_err() || !matches_res.unwrap() {
+            return Err("Certificate does not belong to the private key!".to_string());
+        }
+        self.certificate_chain = certificates;
+        Ok(())
+    }
+
+    /**
+     * Loads the trusted CA certificates (PEM bundle)  
+     * used to verify the certificates of remote endpoints.
+     */
+    pub fn load_trusted_ca(&mut self, path: &str) -> Result<(), Error> {
+        let certificates = load_certificates(path)?;
+        self.trusted_ca = Some(build_store(certificates)?);
+        Ok(())
+    }
+
+    /**
+     * Returns the fingerprint of our public key,  
+     * which remote endpoints can use to pin it.
+     */
+    pub fn fingerprint(&self) -> Option<String> {
+        self.identity_key().ok().map(|private_key| {
+            let public_key_der = private_key.public_key_to_der().unwrap();
+            fingerprint(public_key_der.as_slice())
+        })
+    }
+
+    /**
+     * Returns the raw public key of our static X25519 key in `KeyExchange::Noise` mode,  
+     * which remote endpoints need to connect with `NoisePattern::IK`.
+     */
+    pub fn noise_public_key(&self) -> Option<Vec<u8>> {
+        self.noise_static_key.as_ref()
+            .and_then(|key| x25519_public_bytes(key).ok())
+    }
+
+    /**
+     * Returns our identity key, the static X25519 key in `KeyExchange::Noise` mode,  
+     * the Ed25519 key if configured, the RSA key otherwise.
+     */
+    pub fn identity_key(&self) -> Result<PKey<Private>, Error> {
+        if self.key_exchange == KeyExchange::Noise {
+            if self.noise_static_key.is_none() {
+                return Err("No static key configured!".to_string());
+            }
+            return Ok(
+                self.noise_static_key.clone().unwrap()
+            );
         }
+        if self.ed25519_key.is_some() {
+            return Ok(
+                self.ed25519_key.clone().unwrap()
+            );
+        }
+        if self.private_key.is_none() {
+            return Err("No private key configured!".to_string());
+        }
+        let pkey_res = PKey::from_rsa(self.private_key.clone().unwrap());
+        if pkey_res.is_err() {
+            return Err("Error converting private key!".to_string());
+        }
+        Ok(
+            pkey_res.unwrap()
+        )
+    }
+}
+
+/**
+ * Encodes a private key to PEM (PKCS#8),  
+ * encrypted with AES-256-CBC if a passphrase is given.
+ */
+fn pkcs8_pem(private_key: &PKey<Private>, passphrase: Option<&[u8]>) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    match passphrase {
+        Some(passphrase) => private_key.private_key_to_pem_pkcs8_passphrase(
+            openssl::symm::Cipher::aes_256_cbc(),
+            passphrase
+        ),
+        None => private_key.private_key_to_pem_pkcs8()
     }
 }
 
+/**
+ * Writes an encoded private key to a PEM file which only the owner can read.
+ */
+fn save_private_key_pem(pem_res: Result<Vec<u8>, openssl::error::ErrorStack>, path: &str) -> Result<(), Error> {
+    if pem_res.is_err() {
+        return Err("Error encoding private key to PEM!".to_string());
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let write_res = options.open(path)
+        .and_then(|mut file| file.write_all(pem_res.unwrap().as_slice()));
+    if write_res.is_err() {
+        return Err(format!("Could not write private key file {} !", path));
+    }
+    Ok(())
+}
+
 impl Endpoint {
     /**
      * Creates a new Endpoint and binds it to the current address.
      */
     pub fn new(config: EndpointConfig) -> Result<EndpointArc, Error> {
+        if config.cipher_suites.is_empty() {
+            return Err("At least one cipher suite is required!".to_string());
+        }
+        if config.key_exchange == KeyExchange::Psk {
+            if config.psk.is_none() || config.psk_identity.is_none() {
+                return Err("A pre-shared key and identity are required!".to_string());
+            }
+        } else if config.key_exchange == KeyExchange::Noise {
+            if config.noise_static_key.as_ref().map(|key| key.id()) != Some(Id::X25519) {
+                return Err("A static X25519 key is required!".to_string());
+            }
+        } else if config.key_exchange == KeyExchange::Rsa && config.private_key.is_none() {
+            return Err("A private RSA key is required!".to_string());
+        } else if config.private_key.is_none() && config.ed25519_key.is_none() {
+            return Err("A private key is required!".to_string());
+        }
+        // DTLS authenticates endpoints with their identity key and certificate
+        if config.transport == Transport::Dtls &&
+            (config.key_exchange == KeyExchange::Psk || config.key_exchange == KeyExchange::Noise) {
+            return Err("DTLS requires an RSA or Ed25519 identity key!".to_string());
+        }
+        // DTLS records are framed and encrypted by OpenSSL, which neither masks nor pads them
+        if config.transport == Transport::Dtls &&
+            (config.header_protection || config.padding_policy != PaddingPolicy::None) {
+            return Err("Header protection and padding are not supported with DTLS!".to_string());
+        }
         let socket_res = UdpSocket::bind(&config.address);
         if socket_res.is_err() {
             return Err("Could not bind socket to address!".to_string());
@@ -164,6 +727,21 @@ impl Endpoint {
             )
         ).unwrap_or(());
 
+        let known_hosts = match config.known_hosts_file {
+            Some(ref path) => Some(KnownHosts::load(path)?),
+            None => None
+        };
+
+        let cookie_jar = Arc::new(
+            RwLock::new(
+                CookieJar::new()
+            )
+        );
+        let dtls = match config.transport {
+            Transport::Dtls => Some(DtlsContext::new(&config, cookie_jar.clone())?),
+            Transport::Udps => None
+        };
+
         let endpoint = Endpoint {
             running: AtomicBool::new(true),
             config: config,
@@ -171,6 +749,21 @@ impl Endpoint {
             new_connection_list: RwLock::new(
                 Vec::new()
             ),
+            known_hosts: RwLock::new(
+                known_hosts
+            ),
+            cookie_jar: cookie_jar,
+            ticket_key: Secret::random(KEY_LEN),
+            tickets: RwLock::new(
+                HashMap::new()
+            ),
+            early_data_filter: RwLock::new(
+                EarlyDataFilter::new()
+            ),
+            dtls: dtls,
+            dtls_sessions: RwLock::new(
+                HashMap::new()
+            ),
             connection_list: RwLock::new(
                 HashMap::new()
             ),
@@ -226,6 +819,10 @@ impl Endpoint {
         for id in removal_list.iter() {
             self.disconnect(id);
         }
+        let addresses: Vec<String> = self.dtls_sessions.read().unwrap().keys().cloned().collect();
+        for address in addresses.iter() {
+            self.close_dtls_session(address);
+        }
         if self.receive_thread.read().unwrap().is_some() {
             let handle = self.receive_thread.write().unwrap().take().unwrap();
             handle.join().unwrap_or(());
@@ -242,6 +839,33 @@ impl Endpoint {
      * address and return a `ConnectionArc` corresponding to this connection.
      */
     pub fn connect(&self, addr: &String) -> Result<ConnectionArc, Error> {
+        self.connect_with(addr, None, None)
+    }
+
+    /**
+     * Connect to a another UDPS endpoint with a sealed `ConnectToken` and its  
+     * client key, as required by endpoints with `connect_token_key` configured.
+     */
+    pub fn connect_with_token(&self, addr: &String, connect_token: Vec<u8>, client_key: Secret) -> Result<ConnectionArc, Error> {
+        self.connect_with(addr, Some((connect_token, client_key)), None)
+    }
+
+    /**
+     * Connect to a another UDPS endpoint, sending application data  
+     * with the `Connect` package. It is sealed with a key derived from  
+     * a resumption ticket or the pre-shared key, and only sent without  
+     * either with `EncryptionPolicy::AllowPlaintext`.  
+     * Early data may be replayed by an attacker within `early_data_window`,  
+     * so it should not trigger anything that must only happen once.
+     */
+    pub fn connect_with_early_data(&self, addr: &String, early_data: Vec<u8>) -> Result<ConnectionArc, Error> {
+        self.connect_with(addr, None, Some(early_data))
+    }
+
+    /**
+     * Internal function sending the connection request.
+     */
+    fn connect_with(&self, addr: &String, connect_token: Option<(Vec<u8>, Secret)>, early_data: Option<Vec<u8>>) -> Result<ConnectionArc, Error> {
         let stdout = stdout();
         //writeln!(&mut stdout.lock(), "Connecting UDPS endpoint to {}", addr);
         let mut package = Package::new_default();
@@ -249,7 +873,27 @@ impl Endpoint {
         package.header.ack = true;
         let connection_id = package.header.connection_id;
 
-        let conn_arc = Arc::new(Connection::new(addr, &connection_id));
+        let mut connection = Connection::new(addr, &connection_id);
+        connection.initiator = true;
+        connection.address_validated.store(true, Ordering::Relaxed);
+        if let Some((sealed, client_key)) = connect_token {
+            connection.connect_token = Some(sealed);
+            connection.connect_token_key = Some(client_key);
+        }
+        connection.ticket = RwLock::new(self.resumption_ticket(addr));
+        if early_data.is_some() {
+            if self.config.transport == Transport::Dtls {
+                return Err("Early data is not supported with DTLS!".to_string());
+            }
+            if connection.ticket.read().unwrap().is_none() &&
+                self.config.key_exchange != KeyExchange::Psk &&
+                self.config.encryption_policy == EncryptionPolicy::RequireEncryption {
+                return Err("Early data requires a resumption ticket or pre-shared key!".to_string());
+            }
+        }
+        connection.early_data = early_data;
+        let conn_arc = Arc::new(connection);
+        package.data = self.build_connect_message(&conn_arc)?;
         {
             let mut connection_list = self.connection_list.write().unwrap();
             connection_list.insert(connection_id, conn_arc.clone());
@@ -257,7 +901,20 @@ impl Endpoint {
 
         *conn_arc.state.write().unwrap() = ConnectionState::Connected;
 
-        self.send(package).unwrap();
+        if self.config.transport == Transport::Dtls {
+            // The `Connect` package is sent once the DTLS handshake finished
+            let open_res = self.open_dtls_session(&conn_arc);
+            if let Err(error) = open_res {
+                self.connection_list.write().unwrap().remove(&connection_id);
+                return Err(error);
+            }
+        }
+
+        let send_res = self.send(package);
+        if let Err(error) = send_res {
+            self.connection_list.write().unwrap().remove(&connection_id);
+            return Err(error);
+        }
 
         Ok(
             conn_arc
@@ -279,12 +936,21 @@ impl Endpoint {
             self.send(package).unwrap_or(0);
             let connection = self.connection_list.write().unwrap().remove(connection_id).unwrap();
             *connection.state.write().unwrap() = ConnectionState::Disconnected;
+            // Close the DTLS session once no connection uses it anymore
+            if self.config.transport == Transport::Dtls {
+                let address = dtls_address(&connection.address);
+                let in_use = self.connection_list.read().unwrap().values()
+                    .any(|other| dtls_address(&other.address) == address);
+                if !in_use {
+                    self.close_dtls_session(&address);
+                }
+            }
         }
     }
 
     /**
-     * Receive loop.
-     * This is started automatically in the background,
+     * Receive loop.  
+     * This is started automatically in the background,  
      * and should not be called manually.
      */
     pub fn receive_loop(&self) {
@@ -292,12 +958,18 @@ impl Endpoint {
         let mut running = true;
         while running {
             running = self.running.load(Ordering::Relaxed);
+            if self.config.transport == Transport::Dtls {
+                for (package, addr, size) in self.receive_dtls() {
+                    self.handle_package(addr, package, size);
+                }
+                continue;
+            }
             let recv_res = self.receive();
             if recv_res.is_err() {
                 continue;
             }
-            let (package, addr) = recv_res.unwrap();
-            self.handle_package(addr, package);
+            let (package, addr, size) = recv_res.unwrap();
+            self.handle_package(addr, package, size);
         }
         //writeln!(&mut stdout.lock(), "Shutting down receive_loop");
     }
@@ -305,7 +977,7 @@ impl Endpoint {
     /**
      * Internal method for handling a specific package after receival.
      */
-    fn handle_package(&self, addr: String, package: Package) {
+    pub(crate) fn handle_package(&self, addr: String, mut package: Package, size: usize) {
         let stdout = stdout();
         //writeln!(&mut stdout.lock(), "Package from {} !", addr);
         // Check if there exists a connection:
@@ -321,10 +993,37 @@ impl Endpoint {
             if package.header.method_type != MethodType::Connect {
                 return;
             }
+            // Make sure the remote endpoint owns its address before keeping any state,
+            // DTLS did so with its own cookie already
+            if self.config.cookie_exchange &&
+                self.config.transport == Transport::Udps &&
+                !self.check_cookie(&addr, &package) {
+                return;
+            }
+            let connect_token_res = self.check_connect_token(&package);
+            if connect_token_res.is_err() {
+                return;
+            }
             // The package is a connection request. Create a new connection.
+            let mut new_conn = Connection::new(&addr, &package.header.connection_id);
+            if let Some(connect_token) = connect_token_res.unwrap() {
+                new_conn.client_id = Some(connect_token.client_id);
+                new_conn.user_data = Some(connect_token.user_data.clone());
+                new_conn.connect_token_key = Some(Secret::new(connect_token.client_key.clone()));
+            }
             let new_conn_arc = Arc::new(
-                Connection::new(&addr, &package.header.connection_id)
+                new_conn
             );
+            // The cookie proved that the remote endpoint receives our packages
+            if self.config.cookie_exchange {
+                new_conn_arc.address_validated.store(true, Ordering::Relaxed);
+            }
+            // Packages only arrive through established DTLS sessions
+            if self.config.transport == Transport::Dtls && !self.apply_dtls_peer(&new_conn_arc) {
+                return;
+            }
+            // Queued before anybody sees the connection
+            self.accept_early_data(&new_conn_arc, &package);
 
             *new_conn_arc.state.write().unwrap() = ConnectionState::Connected;
 
@@ -342,7 +1041,48 @@ impl Endpoint {
             let connections = self.connection_list.read().unwrap();
             conn_arc = connections.get(&package.header.connection_id).unwrap().clone();
         }
+
+        // A DTLS session only vouches for the packages of the connections to its own address,
+        // otherwise any remote endpoint could reach the connections of others through it
+        if self.config.transport == Transport::Dtls &&
+            conn_arc.address != addr && dtls_address(&conn_arc.address) != addr {
+            conn_arc.dropped_packages.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
         
+        if !conn_arc.address_validated.load(Ordering::Relaxed) {
+            conn_arc.unvalidated_bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+        }
+
+        // Once the connection is secure, control packages have to be sealed as well,
+        // otherwise anybody on the path could disconnect us or suppress resends
+        if package.header.crypt_type != CryptType::Symm &&
+            self.config.transport == Transport::Udps &&
+            *conn_arc.crypt_state.read().unwrap() == CryptState::Symm {
+            let forged = match package.header.method_type {
+                MethodType::Disconnect => true,
+                MethodType::Ack => !self.acknowledges_handshake(&package),
+                _ => false
+            };
+            if forged {
+                conn_arc.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        // Authenticated packages are readable by anybody, but only
+        // accepted from the remote endpoint if their tag verifies
+        if package.header.crypt_type == CryptType::Auth && self.verify_authenticated(&conn_arc, &mut package).is_err() {
+            conn_arc.dropped_packages.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Only the client of a connect token can seal or tag packages with the secret key,
+        // DTLS sessions keep the token secret already
+        if package.header.crypt_type != CryptType::None || self.config.transport == Transport::Dtls {
+            self.prove_connect_token(&conn_arc);
+        }
+
         // NEXT: Handle package acknowledgement
         // If the incoming package has the `ack` flag set,
         // Immediately send a response acknowledging the package.
@@ -355,23 +1095,45 @@ impl Endpoint {
             response_package.data.resize(data.len(), 0);
             response_package.data.clone_from_slice(&data);
             //writeln!(&mut stdout.lock(), "Sending package ack.");
-            self.send(response_package).unwrap();
+            // Failing to acknowledge is not fatal, the remote endpoint sends the package again
+            self.send(response_package).unwrap_or(0);
         }
 
         match package.header.method_type {
             MethodType::Ack => {
                 self.handle_ack(conn_arc, package);
-                return;
             },
             MethodType::Connect => {
-                return;
+                self.handle_connect(conn_arc, package);
             },
             MethodType::Disconnect => {
                 *conn_arc.state.write().unwrap() = ConnectionState::Disconnected;
                 let mut connection_list = self.connection_list.write().unwrap();
                 connection_list.remove(&conn_arc.id);
-                return;
-            }
+            },
+            MethodType::AsymmKey => {
+                self.handle_asymm_key(conn_arc, package);
+            },
+            MethodType::SymmKey => {
+                self.handle_symm_key(conn_arc, package);
+            },
+            MethodType::Rekey => {
+                self.handle_rekey(conn_arc, package);
+            },
+            MethodType::Cookie => {
+                self.handle_cookie(conn_arc, package);
+            },
+            MethodType::Ticket => {
+                self.handle_ticket(conn_arc, package);
+            },
+            MethodType::Data if !self.accepts_data(&conn_arc, &package) => {
+                conn_arc.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                // Plain data on a secure connection could be forged by anybody on the path,
+                // so only remote endpoints which have not finished the handshake are disconnected
+                if *conn_arc.crypt_state.read().unwrap() != CryptState::Symm {
+                    self.disconnect(&conn_arc.id);
+                }
+            },
             _ => {
                 // For now, just pass the package to the connection.
                 // It will automatically be dropped if its a duplicate.
@@ -381,7 +1143,7 @@ impl Endpoint {
     }
 
     /**
-     * Acknowledgement loop.
+     * Acknowledgement loop.  
      * This is started automatically in the background,  
      * and should not be called manually.
      */
@@ -390,6 +1152,7 @@ impl Endpoint {
         let mut iteration_ms = 0u64;
         let mut remove_list = Vec::new();
         let mut attempt_increase_list = Vec::new();
+        let mut rekey_failed_list = Vec::new();
         //writeln!(&mut stdout.lock(), "starting ack_loop");
         loop_at!((1000 / self.config.ack_loop_time), iteration_ms, {
             //writeln!(&mut stdout.lock(), "ack_loop iteration");
@@ -406,22 +1169,29 @@ impl Endpoint {
                     }
                     let package = package_ack.cached_package.clone();
                     let send_res = self.send(package);
+                    // Packages held back by the amplification limit count as attempts
+                    // as well, so they are given up on eventually
+                    attempt_increase_list.push(*package_id);
                     if send_res.is_err() {
                         //writeln!(&mut stdout.lock(), "Error sending ack package!");
                         continue;
                     }
-                    attempt_increase_list.push(*package_id);
                 }
             }
             // Write actions
             {
                 let mut ack_list = self.ack_list.write().unwrap();
+                // Packages acknowledged in the meantime are gone already
                 for package_id in attempt_increase_list.iter() {
-                    let mut package_ack = ack_list.get_mut(package_id).unwrap();
-                    package_ack.attempts += 1;
+                    if let Some(package_ack) = ack_list.get_mut(package_id) {
+                        package_ack.attempts += 1;
+                    }
                 }
                 for package_id in remove_list.iter() {
-                    let package_ack = ack_list.remove(package_id).unwrap();
+                    let package_ack = match ack_list.remove(package_id) {
+                        Some(package_ack) => package_ack,
+                        None => continue
+                    };
                     if package_ack.cached_package.header.method_type == MethodType::Connect {
                         // Update connection state to Disconnected
                         {
@@ -433,18 +1203,40 @@ impl Endpoint {
                             }
                         }
                     }
+                    if package_ack.cached_package.header.method_type == MethodType::Rekey {
+                        // The remote endpoint may have switched to the offered secret key already,
+                        // offering another one for the same generation could leave us without
+                        // a common key, so the connection is given up on instead
+                        rekey_failed_list.push(package_ack.cached_package.header.connection_id);
+                    }
                 }
             }
+            for connection_id in rekey_failed_list.iter() {
+                self.disconnect(connection_id);
+            }
+            self.cookie_jar.write().unwrap().rotate_if_due(
+                Duration::from_millis(self.config.cookie_rotation)
+            );
+            if self.config.transport == Transport::Dtls {
+                self.poll_dtls_sessions();
+            }
+            // Renew secret keys which exhausted their budget,
+            // and issue resumption tickets for new secure connections
+            for connection in self.collect_connections() {
+                self.check_rekey(&connection);
+                self.check_ticket(&connection);
+            }
             // Clear vectors
             remove_list.clear();
             attempt_increase_list.clear();
+            rekey_failed_list.clear();
         });
 
         //writeln!(&mut stdout.lock(),  "Shutting down ack_loop");
     }
 
     /**
-     * Internal function for handling incoming packages   
+     * Internal function for handling incoming packages  
      * with the `Ack` method.
      */
     fn handle_ack(&self, conn: ConnectionArc, package: Package) {
@@ -457,19 +1249,85 @@ impl Endpoint {
             return;
         }
         let package_ack = package_ack_res.unwrap();
+        // Only an endpoint receiving our packages at its address knows their ids
+        conn.address_validated.store(true, Ordering::Relaxed);
         match package_ack.cached_package.header.method_type {
             MethodType::Connect => {
                 *conn.state.write().unwrap() = ConnectionState::Connected;
+                // Start the key exchange by sending our public key, with pre-shared
+                // keys and Noise it already started with the `Connect` package,
+                // and DTLS took care of it before. With a resumption ticket
+                // we wait whether the remote endpoint accepts it.
+                if self.config.key_exchange != KeyExchange::Psk &&
+                    self.config.key_exchange != KeyExchange::Noise &&
+                    self.config.transport == Transport::Udps &&
+                    conn.ticket.read().unwrap().is_none() {
+                    self.send_public_key(&conn, self.config.cipher_suites.clone(), None).unwrap_or(0);
+                }
+            },
+            MethodType::SymmKey if conn.has_secret() => {
+                // The remote endpoint received our secret key, the connection is now secure.
+                // Rejections of resumption tickets carry none.
+                *conn.crypt_state.write().unwrap() = CryptState::Symm;
+            },
+            MethodType::Rekey => {
+                self.finish_rekey(&conn);
             },
             _ => {}
         };
     }
 
 
+    /**
+     * Internal function checking a received `Data` package against the encryption policy.  
+     * When encryption is required, it must have been sealed, or authenticated if the sender  
+     * chose to leave it readable. The initiating endpoint in `KeyExchange::Rsa` mode may receive  
+     * it before the remote endpoint acknowledged our secret key, but being sealed or tagged with  
+     * it proves that the remote endpoint knows it.
+     */
+    fn accepts_data(&self, conn: &ConnectionArc, package: &Package) -> bool {
+        if self.config.encryption_policy == EncryptionPolicy::AllowPlaintext {
+            return true;
+        }
+        // Packages only arrive through established DTLS sessions
+        if self.config.transport == Transport::Dtls {
+            return *conn.crypt_state.read().unwrap() == CryptState::Symm;
+        }
+        if package.header.crypt_type != CryptType::Symm && package.header.crypt_type != CryptType::Auth {
+            return false;
+        }
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        if *crypt_state == CryptState::Asymm && conn.has_secret() {
+            *crypt_state = CryptState::Symm;
+        }
+        *crypt_state == CryptState::Symm
+    }
+
+    /**
+     * Internal function checking if an `Ack` package acknowledges  
+     * one of our handshake packages. These are acknowledged before  
+     * the remote endpoint knows the secret key, so they are never sealed.
+     */
+    fn acknowledges_handshake(&self, package: &Package) -> bool {
+        if package.data.len() < 4 {
+            return false;
+        }
+        let id = conv_slice_to_u32(package.data.as_slice());
+        let acks = self.ack_list.read().unwrap();
+        match acks.get(&id) {
+            Some(package_ack) => {
+                let method_type = &package_ack.cached_package.header.method_type;
+                *method_type == MethodType::Connect || *method_type == MethodType::AsymmKey ||
+                    *method_type == MethodType::SymmKey
+            },
+            None => false
+        }
+    }
+
     /**
      * Sends a package, and returns the sent size.
      */
-    pub fn send(&self, package: Package) -> Result<usize, Error> {
+    pub fn send(&self, mut package: Package) -> Result<usize, Error> {
         let stdout = stdout();
         //writeln!(&mut stdout.lock(), "Sending package!");
         let exists = {
@@ -486,6 +1344,12 @@ impl Endpoint {
             connections.get(&package.header.connection_id).unwrap().clone()
         };
 
+        if package.header.method_type == MethodType::Data &&
+            self.config.encryption_policy == EncryptionPolicy::RequireEncryption &&
+            *connection.crypt_state.read().unwrap() != CryptState::Symm {
+            return Err("Connection is not secure yet!".to_string());
+        }
+
         let create_ack = { 
             package.header.ack && 
             !self.ack_list.read().unwrap().contains_key(&package.header.package_id)
@@ -498,10 +1362,94 @@ impl Endpoint {
         }
         //writeln!(&mut stdout.lock(), "Sending package!");
 
-        let data: Vec<u8> = package.try_into()?;
-        let send_res = self.socket.send_to(data.as_slice(), &connection.address);
+        // Seal application data and new secret keys once a secret key has been
+        // exchanged, and control packages once the connection is secure. The acknowledgement
+        // cache keeps the plain package, so every resend is sealed with a fresh packet
+        // number, and with it a fresh nonce, and the then current secret key.
+        let sealed = match package.header.method_type {
+            MethodType::Data | MethodType::Rekey | MethodType::Ticket => true,
+            MethodType::Ack | MethodType::Disconnect => {
+                *connection.crypt_state.read().unwrap() == CryptState::Symm
+            },
+            _ => false
+        };
+        // DTLS sessions protect all packages already
+        if self.config.transport == Transport::Dtls {
+            package.header.crypt_type = CryptType::None;
+        }
+        // Application data can be authenticated instead, leaving it readable on the path
+        let authenticated = package.header.method_type == MethodType::Data &&
+            package.header.crypt_type == CryptType::Auth;
+        let current_keys = connection.get_current_session_keys();
+        if authenticated {
+            if current_keys.is_none() {
+                return Err("Connection has no secret key yet!".to_string());
+            }
+            let (key_epoch, session_keys) = current_keys.unwrap();
+            connection.record_usage(package.data.len());
+            package.header.cipher_suite = None;
+            package.header.key_epoch = key_epoch;
+            package.header.packet_number = connection.next_packet_number.fetch_add(1, Ordering::Relaxed);
+            let tag = SessionKeys::auth_tag(
+                session_keys.auth_key(connection.initiator),
+                package.header.associated_data()?.as_slice(),
+                package.data.as_slice()
+            )?;
+            package.data.extend_from_slice(tag.as_slice());
+        } else if sealed && current_keys.is_some() {
+            let (key_epoch, session_keys) = current_keys.unwrap();
+            let cipher_suite = connection.get_cipher_suite().unwrap();
+            // Application data is padded inside the sealed payload, so its length is hidden
+            // and never padded beyond what the remote endpoint can receive
+            if package.header.method_type == MethodType::Data {
+                let max_len = (self.config.buffer_size as usize)
+                    .saturating_sub(self.config.max_datagram_padding)
+                    .saturating_sub(PADDING_OVERHEAD);
+                self.config.padding_policy.pad(&mut package.data, max_len)?;
+            }
+            connection.record_usage(package.data.len());
+            package.header.crypt_type = CryptType::Symm;
+            package.header.cipher_suite = Some(cipher_suite);
+            package.header.key_epoch = key_epoch;
+            package.header.packet_number = connection.next_packet_number.fetch_add(1, Ordering::Relaxed);
+            let (key, iv) = session_keys.direction(connection.initiator);
+            package.data = cipher_suite.cipher().seal_with_nonce(
+                key,
+                SessionKeys::nonce(iv, package.header.packet_number).as_slice(),
+                package.header.associated_data()?.as_slice(),
+                package.data.as_slice()
+            )?;
+        }
+
+        let data = if self.config.transport == Transport::Dtls {
+            package.try_into()?
+        } else {
+            self.encode_package(Some(&connection), package)?
+        };
+        // Never let a spoofed address make us send much more than we received from it
+        if !connection.address_validated.load(Ordering::Relaxed) {
+            let limit = connection.unvalidated_bytes_received.load(Ordering::Relaxed)
+                .saturating_mul(self.config.amplification_factor);
+            let sent = connection.unvalidated_bytes_sent.load(Ordering::Relaxed);
+            if sent + data.len() as u64 > limit {
+                return Err(format!("Amplification limit reached for {} !", &connection.address));
+            }
+            connection.unvalidated_bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        if self.config.transport == Transport::Dtls {
+            return self.dtls_send(data.as_slice(), &connection.address);
+        }
+        self.send_raw(data.as_slice(), &connection.address)
+    }
+
+    /**
+     * Internal function sending encoded data to an address,  
+     * without looking up its connection.
+     */
+    pub(crate) fn send_raw(&self, data: &[u8], address: &String) -> Result<usize, Error> {
+        let send_res = self.socket.send_to(data, address);
         if send_res.is_err() {
-            return Err(format!("Unknown error sending package to {} !", &connection.address));
+            return Err(format!("Unknown error sending package to {} !", address));
         }
         Ok(
             send_res.unwrap()
@@ -509,9 +1457,9 @@ impl Endpoint {
     }
 
     /**
-     * Receives a package, and returns it and the sender address.
+     * Receives a package, and returns it, the sender address and the received size.
      */
-    fn receive(&self) -> Result<(Package, String), Error> {
+    fn receive(&self) -> Result<(Package, String, usize), Error> {
         let mut data: Vec<u8> = Vec::new();
         data.resize(self.config.buffer_size as usize, 0);
         let recv_res = self.socket.recv_from(data.as_mut_slice());
@@ -520,12 +1468,84 @@ impl Endpoint {
         }
         let (real_size, addr) = recv_res.unwrap();
         data.resize(real_size, 0);
-        let package = Package::try_from(data)?;
+        let mut package = self.decode_package(data)?;
+
+        // Open sealed packages, dropping them if they or their header are not authentic
+        if package.header.crypt_type == CryptType::Symm {
+            let connection = {
+                let connections = self.connection_list.read().unwrap();
+                connections.get(&package.header.connection_id).cloned()
+            };
+            if connection.is_none() {
+                return Err("Sealed package from unknown connection!".to_string());
+            }
+            let connection = connection.unwrap();
+            let cipher_suite = connection.get_cipher_suite();
+            let session_keys = connection.get_session_keys_for_epoch(package.header.key_epoch);
+            if session_keys.is_none() || package.header.cipher_suite != cipher_suite {
+                connection.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                return Err("Sealed package with unknown secret key or wrong cipher suite!".to_string());
+            }
+            // The remote endpoint sealed the package with the keys of its direction
+            let session_keys = session_keys.unwrap();
+            let (key, iv) = session_keys.direction(!connection.initiator);
+            let open_res = package.header.associated_data().and_then(|associated_data| {
+                cipher_suite.unwrap().cipher().open_with_nonce(
+                    key,
+                    SessionKeys::nonce(iv, package.header.packet_number).as_slice(),
+                    associated_data.as_slice(),
+                    package.data.as_slice()
+                )
+            });
+            if let Err(error) = open_res {
+                connection.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                return Err(error);
+            }
+            // The packet number is authenticated now, reject it if we have seen it before
+            if !connection.replay_window.write().unwrap().accept(package.header.packet_number) {
+                connection.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                return Err("Replayed package!".to_string());
+            }
+            package.data = open_res.unwrap();
+            connection.record_usage(package.data.len());
+            if package.header.method_type == MethodType::Data && PaddingPolicy::unpad(&mut package.data).is_err() {
+                connection.dropped_packages.fetch_add(1, Ordering::Relaxed);
+                return Err("Sealed package without padding marker!".to_string());
+            }
+        }
         Ok(
-            (package, addr.to_string())
+            (package, addr.to_string(), real_size)
         )   
     }
 
+    /**
+     * Internal function verifying and removing the tag of an authenticated package,  
+     * which must be tagged with a secret key of the connection and not replayed.
+     */
+    fn verify_authenticated(&self, connection: &ConnectionArc, package: &mut Package) -> Result<(), Error> {
+        let session_keys = connection.get_session_keys_for_epoch(package.header.key_epoch);
+        if session_keys.is_none() || package.data.len() < AUTH_TAG_LEN {
+            return Err("Authenticated package with unknown secret key!".to_string());
+        }
+        let payload_len = package.data.len() - AUTH_TAG_LEN;
+        // The remote endpoint tagged the package with the key of its direction
+        let tag = SessionKeys::auth_tag(
+            session_keys.unwrap().auth_key(!connection.initiator),
+            package.header.associated_data()?.as_slice(),
+            &package.data[..payload_len]
+        )?;
+        if !constant_time_eq(tag.as_slice(), &package.data[payload_len..]) {
+            return Err("Authenticated package was altered!".to_string());
+        }
+        // The packet number is authenticated now, reject it if we have seen it before
+        if !connection.replay_window.write().unwrap().accept(package.header.packet_number) {
+            return Err("Replayed package!".to_string());
+        }
+        package.data.truncate(payload_len);
+        connection.record_usage(payload_len);
+        Ok(())
+    }
+
     pub fn collect_connections(&self) -> Vec<ConnectionArc> {
         let connections = self.connection_list.read().unwrap();
         connections.iter().map(|(_, c)| c.clone()).collect()
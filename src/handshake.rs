@@ -0,0 +1,1707 @@
+use std::convert::*;
+use std::vec::*;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use rmps::*;
+use openssl::rsa::*;
+use openssl::pkey::*;
+use openssl::x509::X509;
+
+use crate::prelude::*;
+
+/**
+ * Key exchange used to establish the secret key
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyExchange {
+    /**
+     * The initiating endpoint generates the secret key and sends  
+     * it encrypted with the remote public RSA key
+     */
+    Rsa = 0,
+    /**
+     * Both endpoints exchange ephemeral X25519 keys signed with their RSA  
+     * identity key, and derive the secret key from the shared secret.  
+     * Recorded traffic stays secret even if a private key leaks later on.
+     */
+    Ecdh,
+    /**
+     * Both endpoints share a pre-shared key and derive the secret key from  
+     * it and nonces exchanged with the `Connect` and a `SymmKey` package.  
+     * No public key cryptography is needed, and the handshake takes a single round trip.
+     */
+    Psk,
+    /**
+     * Both endpoints run a standard Noise handshake (`Noise_XX` or `Noise_IK`  
+     * with Curve25519, ChaChaPoly and SHA-256) with their static X25519 keys,  
+     * carried by the `Connect`, `AsymmKey` and `SymmKey` packages.
+     */
+    Noise,
+}
+
+/**
+ * Payload of `AsymmKey` packages
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PublicKeyMessage {
+    /**
+     * Public RSA key of the sender (binary DER)
+     */
+    pub public_key: Vec<u8>,
+    /**
+     * Cipher suites supported by the initiating endpoint in order of preference,  
+     * or the single suite chosen by the accepting endpoint
+     */
+    pub cipher_suites: Vec<CipherSuite>,
+    /**
+     * Certificate chain of the sender (binary DER, leaf first),  
+     * replacing `public_key` if not empty
+     */
+    pub certificates: Vec<Vec<u8>>,
+    /**
+     * Ephemeral X25519 public key (`KeyExchange::Ecdh` only)
+     */
+    pub ephemeral_key: Option<Vec<u8>>,
+    /**
+     * Signature of the handshake transcript with the  
+     * senders RSA key (`KeyExchange::Ecdh` only)
+     */
+    pub signature: Option<Vec<u8>>,
+    /**
+     * Random nonce of the sender (`KeyExchange::Rsa` only)
+     */
+    pub nonce: Option<Vec<u8>>,
+}
+
+impl PublicKeyMessage {
+    /**
+     * Builds the data signed by an endpoint in `KeyExchange::Ecdh` mode.  
+     * The accepting endpoint additionally signs the ephemeral key and  
+     * cipher suites offered by the initiating endpoint, binding its  
+     * answer to this very handshake.
+     */
+    pub fn transcript(
+        connection_id: u32,
+        initiator: bool,
+        ephemeral_key: &[u8],
+        cipher_suites: &[CipherSuite],
+        remote: Option<(&[u8], &[CipherSuite])>
+    ) -> Vec<u8> {
+        let mut transcript = b"udps ecdh".to_vec();
+        transcript.extend_from_slice(&conv_u32_to_bytes(&connection_id));
+        transcript.push(initiator as u8);
+        transcript.extend_from_slice(ephemeral_key);
+        transcript.extend(cipher_suites.iter().map(|suite| *suite as u8));
+        if let Some((remote_ephemeral_key, remote_cipher_suites)) = remote {
+            transcript.extend_from_slice(remote_ephemeral_key);
+            transcript.extend(remote_cipher_suites.iter().map(|suite| *suite as u8));
+        }
+        transcript
+    }
+}
+
+/**
+ * Payload of the `SymmKey` package sent by the initiating endpoint  
+ * in `KeyExchange::Rsa` mode
+ */
+#[derive(Serialize, Deserialize)]
+pub struct SecretKeyMessage {
+    /**
+     * Secret key encrypted with the public RSA key of the accepting endpoint
+     */
+    pub encrypted_key: Vec<u8>,
+    /**
+     * Signature of the handshake transcript with the key of the initiating endpoint,  
+     * proving that it holds the private key of the public key or certificate it sent
+     */
+    pub signature: Vec<u8>,
+}
+
+impl SecretKeyMessage {
+    /**
+     * Builds the data signed by the initiating endpoint in `KeyExchange::Rsa` mode,  
+     * binding both public keys, both nonces, the chosen cipher suite  
+     * and the encrypted secret key to this very handshake.
+     */
+    pub fn transcript(
+        connection_id: u32,
+        initiator_public_key: &[u8],
+        acceptor_public_key: &[u8],
+        initiator_nonce: &[u8],
+        acceptor_nonce: &[u8],
+        cipher_suite: CipherSuite,
+        encrypted_key: &[u8]
+    ) -> Vec<u8> {
+        let mut transcript = b"udps rsa".to_vec();
+        transcript.extend_from_slice(&conv_u32_to_bytes(&connection_id));
+        for part in [initiator_public_key, acceptor_public_key, initiator_nonce, acceptor_nonce].iter() {
+            transcript.extend_from_slice(&(part.len() as u32).to_le_bytes());
+            transcript.extend_from_slice(part);
+        }
+        transcript.push(cipher_suite as u8);
+        transcript.extend_from_slice(encrypted_key);
+        transcript
+    }
+}
+
+/**
+ * Payload of `Connect` packages
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectMessage {
+    /**
+     * Cookie sent by the accepting endpoint with the `Cookie` method, if any
+     */
+    pub cookie: Option<Vec<u8>>,
+    /**
+     * Sealed connect token, if any
+     */
+    pub connect_token: Option<Vec<u8>>,
+    /**
+     * Identity of the pre-shared key in `KeyExchange::Psk` mode
+     */
+    pub psk_identity: Option<String>,
+    /**
+     * Random nonce of the initiating endpoint in `KeyExchange::Psk` mode,  
+     * or if a resumption ticket or early data is sent
+     */
+    pub nonce: Option<Vec<u8>>,
+    /**
+     * Supported cipher suites in order of preference
+     */
+    pub cipher_suites: Vec<CipherSuite>,
+    /**
+     * Noise pattern chosen by the initiating endpoint in `KeyExchange::Noise` mode
+     */
+    pub noise_pattern: Option<NoisePattern>,
+    /**
+     * First Noise handshake message in `KeyExchange::Noise` mode
+     */
+    pub noise_message: Option<Vec<u8>>,
+    /**
+     * Sealed resumption ticket issued by the accepting endpoint, if any
+     */
+    pub ticket: Option<Vec<u8>>,
+    /**
+     * Application data sent before the handshake finished, if any (`EarlyData`)
+     */
+    pub early_data: Option<Vec<u8>>,
+}
+
+/**
+ * Payload of the `SymmKey` package answering a `Connect` package  
+ * in `KeyExchange::Psk` mode
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PskMessage {
+    /**
+     * Random nonce of the accepting endpoint
+     */
+    pub nonce: Vec<u8>,
+    /**
+     * Cipher suite chosen by the accepting endpoint
+     */
+    pub cipher_suite: CipherSuite,
+    /**
+     * True if the early data of the `Connect` package was accepted
+     */
+    pub early_data_accepted: bool,
+    /**
+     * MAC of both nonces with the derived secret key,  
+     * proving that the accepting endpoint knows the pre-shared key
+     */
+    pub mac: Vec<u8>,
+}
+
+impl PskMessage {
+    /**
+     * Derives the secret key from the pre-shared key and both nonces.
+     */
+    pub fn derive_secret(psk: &Secret, identity: &String, initiator_nonce: &[u8], acceptor_nonce: &[u8]) -> Result<Secret, String> {
+        let mut info = b"udps psk secret key".to_vec();
+        info.extend_from_slice(identity.as_bytes());
+        info.extend_from_slice(initiator_nonce);
+        info.extend_from_slice(acceptor_nonce);
+        hmac_sha256(psk.as_bytes(), info.as_slice()).map(Secret::new)
+    }
+
+    /**
+     * Computes the MAC proving knowledge of the derived secret key.
+     */
+    pub fn compute_mac(
+        secret_key: &Secret,
+        initiator_nonce: &[u8],
+        acceptor_nonce: &[u8],
+        cipher_suite: CipherSuite,
+        early_data_accepted: bool
+    ) -> Result<Vec<u8>, String> {
+        let mut info = b"udps psk finished".to_vec();
+        info.extend_from_slice(initiator_nonce);
+        info.extend_from_slice(acceptor_nonce);
+        info.push(cipher_suite as u8);
+        info.push(early_data_accepted as u8);
+        hmac_sha256(secret_key.as_bytes(), info.as_slice())
+    }
+}
+
+/**
+ * Implements MessagePack encoding and decoding of handshake messages
+ * via `TryFrom<Vec<u8>>` and `TryInto<Vec<u8>>`, like for packages.
+ */
+macro_rules! impl_message_codec {
+    ($t:ty, $name:expr) => {
+        impl TryFrom<Vec<u8>> for $t {
+            type Error = String;
+
+            /**
+             * Try decoding from binary (MessagePack encoded)
+             */
+            fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+                let message_res = from_slice::<Self>(data.as_slice());
+                if message_res.is_err() {
+                    return Err(format!("Unknown error decoding MessagePack {}!", $name));
+                }
+                Ok(
+                    message_res.unwrap()
+                )
+            }
+        }
+
+        impl TryInto<Vec<u8>> for $t {
+            type Error = String;
+
+            /**
+             * Try encoding into binary (MessagePack encoded)
+             */
+            fn try_into(self) -> Result<Vec<u8>, Self::Error> {
+                let encode_res = to_vec(&self);
+                if encode_res.is_err() {
+                    return Err(format!("Unknown error encoding MessagePack {}!", $name));
+                }
+                Ok(
+                    encode_res.unwrap()
+                )
+            }
+        }
+    };
+}
+
+/**
+ * Payload of `Rekey` packages, sealed with the current secret key
+ */
+#[derive(Serialize, Deserialize)]
+pub struct RekeyMessage {
+    /**
+     * Generation of the new secret key
+     */
+    pub epoch: u32,
+    /**
+     * The new secret key
+     */
+    pub secret_key: Vec<u8>,
+}
+
+/**
+ * Wiping the new secret key once the message is encoded or handled
+ */
+impl Drop for RekeyMessage {
+    fn drop(&mut self) {
+        zeroize(self.secret_key.as_mut_slice());
+    }
+}
+
+/**
+ * Payload of `Ticket` packages, sealed with the current secret key
+ */
+#[derive(Serialize, Deserialize)]
+pub struct TicketMessage {
+    /**
+     * Sealed resumption ticket, only the issuing endpoint can open it
+     */
+    pub ticket: Vec<u8>,
+    /**
+     * Time (in ms) the ticket stays valid
+     */
+    pub lifetime: u64,
+}
+
+/**
+ * Payload of the `SymmKey` package answering a `Connect` package  
+ * with a resumption ticket
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ResumeMessage {
+    /**
+     * False if the ticket was not accepted, and the full handshake follows
+     */
+    pub accepted: bool,
+    /**
+     * True if the early data of the `Connect` package was accepted
+     */
+    pub early_data_accepted: bool,
+    /**
+     * Random nonce of the accepting endpoint
+     */
+    pub nonce: Vec<u8>,
+    /**
+     * MAC of both nonces with the derived secret key,  
+     * proving that the accepting endpoint could open the ticket
+     */
+    pub mac: Vec<u8>,
+}
+
+impl ResumeMessage {
+    /**
+     * Derives the secret key of the resumed connection from  
+     * the resumption secret of the ticket and both nonces.
+     */
+    pub fn derive_secret(resumption_secret: &Secret, initiator_nonce: &[u8], acceptor_nonce: &[u8]) -> Result<Secret, String> {
+        let mut info = b"udps resumption secret key".to_vec();
+        info.extend_from_slice(initiator_nonce);
+        info.extend_from_slice(acceptor_nonce);
+        hmac_sha256(resumption_secret.as_bytes(), info.as_slice()).map(Secret::new)
+    }
+
+    /**
+     * Computes the MAC proving knowledge of the derived secret key.
+     */
+    pub fn compute_mac(secret_key: &Secret, initiator_nonce: &[u8], acceptor_nonce: &[u8], early_data_accepted: bool) -> Result<Vec<u8>, String> {
+        let mut info = b"udps resumption finished".to_vec();
+        info.extend_from_slice(initiator_nonce);
+        info.extend_from_slice(acceptor_nonce);
+        info.push(early_data_accepted as u8);
+        hmac_sha256(secret_key.as_bytes(), info.as_slice())
+    }
+}
+
+impl_message_codec!(PublicKeyMessage, "public key message");
+impl_message_codec!(SecretKeyMessage, "secret key message");
+impl_message_codec!(RekeyMessage, "rekey message");
+impl_message_codec!(ConnectMessage, "connect message");
+impl_message_codec!(PskMessage, "pre-shared key message");
+impl_message_codec!(TicketMessage, "ticket message");
+impl_message_codec!(ResumeMessage, "resume message");
+
+impl Endpoint {
+    /**
+     * Internal function building the payload of the `Connect` package.
+     */
+    pub(crate) fn build_connect_message(&self, conn: &ConnectionArc) -> Result<Vec<u8>, Error> {
+        let mut message = ConnectMessage {
+            cookie: conn.cookie.read().unwrap().clone(),
+            connect_token: conn.connect_token.clone(),
+            psk_identity: None,
+            nonce: None,
+            cipher_suites: self.config.cipher_suites.clone(),
+            noise_pattern: None,
+            noise_message: None,
+            ticket: conn.ticket.read().unwrap().as_ref().map(|ticket| ticket.ticket.clone()),
+            early_data: None
+        };
+        if self.config.key_exchange == KeyExchange::Psk || message.ticket.is_some() || conn.early_data.is_some() {
+            // Keep our nonce if the package is rebuilt with a cookie
+            let mut handshake_nonce = conn.handshake_nonce.write().unwrap();
+            if handshake_nonce.is_none() {
+                *handshake_nonce = Some(generate_random_bytes(KEY_LEN));
+            }
+            message.nonce = handshake_nonce.clone();
+        }
+        if let Some(ref early_data) = conn.early_data {
+            let key = self.early_data_key(conn, message.nonce.as_ref().unwrap().as_slice())?;
+            // Sealed with the suite negotiated for the ticket, our preferred one otherwise
+            let cipher_suite = conn.ticket.read().unwrap().as_ref()
+                .map(|ticket| ticket.cipher_suite)
+                .or_else(|| self.config.cipher_suites.first().cloned());
+            if cipher_suite.is_none() {
+                return Err("No cipher suite to seal early data with!".to_string());
+            }
+            message.early_data = Some(EarlyData::new(early_data.clone()).seal(
+                key.as_ref(),
+                cipher_suite.unwrap(),
+                conn.id
+            )?);
+        }
+        if self.config.key_exchange == KeyExchange::Psk {
+            message.psk_identity = self.config.psk_identity.clone();
+        }
+        if self.config.key_exchange == KeyExchange::Noise {
+            // Keep our first message if the package is rebuilt with a cookie,
+            // the accepting endpoint may already answer an earlier one
+            let mut noise_message = conn.noise_message.write().unwrap();
+            if noise_message.is_none() {
+                let mut handshake = NoiseHandshake::new(
+                    self.config.noise_pattern,
+                    true,
+                    self.config.noise_static_key.clone().unwrap(),
+                    self.config.noise_remote_key.clone(),
+                    noise_prologue(conn.id, self.config.cipher_suites.as_slice()).as_slice()
+                )?;
+                *noise_message = Some(handshake.write_message(&[])?);
+                *conn.noise.write().unwrap() = Some(handshake);
+            }
+            message.noise_pattern = Some(self.config.noise_pattern);
+            message.noise_message = noise_message.clone();
+        }
+        message.try_into()
+    }
+
+    /**
+     * Internal function checking the cookie of a `Connect` package  
+     * from an unknown connection. Without a valid cookie, a new one is  
+     * sent back with the `Cookie` method, and no state is kept.
+     */
+    pub(crate) fn check_cookie(&self, addr: &String, package: &Package) -> bool {
+        let cookie_jar = self.cookie_jar.read().unwrap();
+        let cookie = ConnectMessage::try_from(package.data.clone())
+            .ok()
+            .and_then(|message| message.cookie);
+        if cookie.is_some() && cookie_jar.verify(addr, package.header.connection_id, cookie.unwrap().as_slice()) {
+            return true;
+        }
+        let cookie_res = cookie_jar.generate(addr, package.header.connection_id);
+        if cookie_res.is_err() {
+            return false;
+        }
+        let mut response_package = Package::new_default();
+        response_package.header.connection_id = package.header.connection_id;
+        response_package.header.method_type = MethodType::Cookie;
+        response_package.data = cookie_res.unwrap();
+        // Sent without any state, it is never larger than the `Connect` package by much
+        let data_res = self.encode_package(None, response_package);
+        if let Ok(data) = data_res {
+            self.send_raw(data.as_slice(), addr).unwrap_or(0);
+        }
+        false
+    }
+
+    /**
+     * Internal function checking the connect token of a `Connect` package  
+     * from an unknown connection, if `connect_token_key` is configured.  
+     * The token must be authentic, unexpired, issued for this endpoint and  
+     * its client must not be connected already.
+     */
+    pub(crate) fn check_connect_token(&self, package: &Package) -> Result<Option<ConnectToken>, Error> {
+        if self.config.connect_token_key.is_none() {
+            return Ok(None);
+        }
+        let message_res = ConnectMessage::try_from(package.data.clone());
+        if message_res.is_err() {
+            return Err("Invalid connect message!".to_string());
+        }
+        let sealed = message_res.unwrap().connect_token;
+        if sealed.is_none() {
+            return Err("Connect token missing!".to_string());
+        }
+        let token = ConnectToken::open(
+            self.config.connect_token_key.as_ref().unwrap().as_bytes(),
+            sealed.unwrap().as_slice()
+        )?;
+        if token.is_expired() {
+            return Err("Connect token expired!".to_string());
+        }
+        if token.client_key.len() != KEY_LEN {
+            return Err("Connect token without client key!".to_string());
+        }
+        let mut addresses = vec![self.config.address.clone()];
+        if let Ok(local_addr) = self.socket.local_addr() {
+            addresses.push(local_addr.to_string());
+        }
+        if !token.allows(&addresses) {
+            return Err("Connect token not issued for this endpoint!".to_string());
+        }
+        // A client may already be connected, its earlier connection is
+        // replaced once this one proves that it knows the client key
+        Ok(
+            Some(token)
+        )
+    }
+
+    /**
+     * Internal function replacing the earlier connections of the client of  
+     * a connect token, once the remote endpoint proved that it knows its client key.  
+     * Anybody may replay a sealed token, but only its client can use the connection.
+     */
+    pub(crate) fn prove_connect_token(&self, conn: &ConnectionArc) {
+        if conn.client_id.is_none() || conn.connect_token_proven.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let replaced: Vec<u32> = self.connection_list.read().unwrap().values()
+            .filter(|connection| connection.id != conn.id && connection.client_id == conn.client_id)
+            .map(|connection| connection.id)
+            .collect();
+        for connection_id in replaced.iter() {
+            self.disconnect(connection_id);
+        }
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `Cookie` method.  
+     * Our pending `Connect` package is sent again with the cookie.
+     */
+    pub(crate) fn handle_cookie(&self, conn: ConnectionArc, package: Package) {
+        if !conn.initiator || *conn.crypt_state.read().unwrap() != CryptState::None {
+            return;
+        }
+        *conn.cookie.write().unwrap() = Some(package.data);
+        let data_res = self.build_connect_message(&conn);
+        if data_res.is_err() {
+            return;
+        }
+        // Replace the cached `Connect` package, so resends carry the cookie as well.
+        // Once it was acknowledged, there is nothing to do.
+        let connect_package = {
+            let mut acks = self.ack_list.write().unwrap();
+            acks.values_mut()
+                .find(|package_ack| {
+                    package_ack.cached_package.header.method_type == MethodType::Connect &&
+                    package_ack.cached_package.header.connection_id == conn.id
+                })
+                .map(|package_ack| {
+                    package_ack.cached_package.data = data_res.unwrap();
+                    package_ack.cached_package.clone()
+                })
+        };
+        if let Some(connect_package) = connect_package {
+            self.send(connect_package).unwrap_or(0);
+        }
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `Connect` method.  
+     * In `KeyExchange::Psk` mode the secret key is derived right away,  
+     * and our nonce and the chosen cipher suite sent back with the `SymmKey` method.  
+     * In `KeyExchange::Rsa` and `KeyExchange::Ecdh` mode a resumption ticket may skip the handshake.
+     */
+    pub(crate) fn handle_connect(&self, conn: ConnectionArc, package: Package) {
+        if self.config.key_exchange == KeyExchange::Noise {
+            self.handle_noise_connect(conn, package);
+            return;
+        }
+        if self.config.key_exchange == KeyExchange::Rsa || self.config.key_exchange == KeyExchange::Ecdh {
+            self.handle_resumption(conn, package);
+            return;
+        }
+        if self.config.key_exchange != KeyExchange::Psk {
+            return;
+        }
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        // Retransmitted `Connect` packages are already acknowledged, nothing else to do
+        if conn.initiator || *crypt_state != CryptState::None {
+            return;
+        }
+        let message_res = ConnectMessage::try_from(package.data);
+        let psk_identity = self.config.psk_identity.as_ref().unwrap();
+        let accepted = message_res.ok().and_then(|message| {
+            let initiator_nonce = message.nonce.unwrap_or(Vec::new());
+            if message.psk_identity.as_ref() != Some(psk_identity) || initiator_nonce.len() != KEY_LEN {
+                return None;
+            }
+            CipherSuite::negotiate(&message.cipher_suites, &self.config.cipher_suites)
+                .map(|cipher_suite| (initiator_nonce, cipher_suite))
+        });
+        if accepted.is_none() {
+            // Unknown identity or no mutually supported cipher suite
+            drop(crypt_state);
+            self.disconnect(&conn.id);
+            return;
+        }
+        let (initiator_nonce, cipher_suite) = accepted.unwrap();
+        let nonce = generate_random_bytes(KEY_LEN);
+        let psk = self.config.psk.as_ref().unwrap();
+        let early_data_accepted = conn.early_data_accepted.load(Ordering::Relaxed);
+        let answer_res = PskMessage::derive_secret(psk, psk_identity, &initiator_nonce, &nonce)
+            .and_then(|secret_key| {
+                let mac = PskMessage::compute_mac(&secret_key, &initiator_nonce, &nonce, cipher_suite, early_data_accepted)?;
+                Ok(
+                    (secret_key, mac)
+                )
+            });
+        if answer_res.is_err() {
+            return;
+        }
+        let (secret_key, mac) = answer_res.unwrap();
+        conn.set_secret(secret_key);
+        *conn.cipher_suite.write().unwrap() = Some(cipher_suite);
+        *crypt_state = CryptState::Symm;
+        drop(crypt_state);
+
+        let answer = PskMessage {
+            nonce: nonce,
+            cipher_suite: cipher_suite,
+            early_data_accepted: early_data_accepted,
+            mac: mac
+        };
+        let mut response_package = Package::new_default();
+        response_package.header.connection_id = conn.id;
+        response_package.header.method_type = MethodType::SymmKey;
+        response_package.header.ack = true;
+        let data_res = answer.try_into();
+        if data_res.is_err() {
+            return;
+        }
+        response_package.data = data_res.unwrap();
+        self.send(response_package).unwrap_or(0);
+    }
+
+    /**
+     * Internal function for sending our public key (binary DER)  
+     * with the `AsymmKey` method, together with the supported  
+     * or chosen cipher suites.  
+     * In `KeyExchange::Ecdh` mode a fresh ephemeral key is generated,  
+     * and `remote` holds the message being answered, if any.
+     */
+    pub(crate) fn send_public_key(
+        &self,
+        conn: &ConnectionArc,
+        cipher_suites: Vec<CipherSuite>,
+        remote: Option<&PublicKeyMessage>
+    ) -> Result<usize, Error> {
+        let mut message = PublicKeyMessage {
+            public_key: Vec::new(),
+            cipher_suites: cipher_suites,
+            certificates: Vec::new(),
+            ephemeral_key: None,
+            signature: None,
+            nonce: None
+        };
+        let private_key = self.config.identity_key()?;
+        if self.config.certificate_chain.is_empty() {
+            let pubkey_der_res = private_key.public_key_to_der();
+            if pubkey_der_res.is_err() {
+                return Err("Error encoding public key to DER!".to_string());
+            }
+            message.public_key = pubkey_der_res.unwrap();
+        } else {
+            for certificate in self.config.certificate_chain.iter() {
+                let der_res = certificate.to_der();
+                if der_res.is_err() {
+                    return Err("Error encoding certificate to DER!".to_string());
+                }
+                message.certificates.push(der_res.unwrap());
+            }
+        }
+        if self.config.key_exchange == KeyExchange::Ecdh {
+            let ephemeral_key = generate_x25519()?;
+            let ephemeral_public = x25519_public_bytes(&ephemeral_key)?;
+            let transcript = PublicKeyMessage::transcript(
+                conn.id,
+                conn.initiator,
+                ephemeral_public.as_slice(),
+                message.cipher_suites.as_slice(),
+                remote.map(|remote| (
+                    remote.ephemeral_key.as_ref().unwrap().as_slice(),
+                    remote.cipher_suites.as_slice()
+                ))
+            );
+            message.signature = Some(sign(&private_key, transcript.as_slice())?);
+            message.ephemeral_key = Some(ephemeral_public);
+            *conn.ephemeral_key.write().unwrap() = Some(ephemeral_key);
+        }
+        if self.config.key_exchange == KeyExchange::Rsa {
+            // Keep our nonce if the package is built again
+            let mut handshake_nonce = conn.handshake_nonce.write().unwrap();
+            if handshake_nonce.is_none() {
+                *handshake_nonce = Some(generate_random_bytes(KEY_LEN));
+            }
+            message.nonce = handshake_nonce.clone();
+        }
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.header.method_type = MethodType::AsymmKey;
+        package.header.ack = true;
+        package.data = message.try_into()?;
+        self.send(package)
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `AsymmKey` method.  
+     * The remote public key is stored, after which the accepting endpoint  
+     * answers with its own public key and the chosen cipher suite.  
+     * In `KeyExchange::Rsa` mode the initiating endpoint then generates  
+     * the secret key and sends it encrypted with the remote public key,  
+     * in `KeyExchange::Ecdh` mode both endpoints derive it on their own.
+     */
+    pub(crate) fn handle_asymm_key(&self, conn: ConnectionArc, package: Package) {
+        if self.config.key_exchange == KeyExchange::Noise {
+            self.handle_noise_message(conn, package);
+            return;
+        }
+        let message_res = PublicKeyMessage::try_from(package.data);
+        if message_res.is_err() {
+            return;
+        }
+        let message = message_res.unwrap();
+        let cipher_suite = CipherSuite::negotiate(&message.cipher_suites, &self.config.cipher_suites);
+        let ecdh = self.config.key_exchange == KeyExchange::Ecdh;
+        let rejected = {
+            // Retransmitted public keys are already acknowledged, nothing else to do.
+            // Checked first, so unsealed public key messages can never tear down a secure connection
+            let mut crypt_state = conn.crypt_state.write().unwrap();
+            if *crypt_state != CryptState::None {
+                return;
+            }
+            // The accepting endpoint picks from the offered suites, the initiating
+            // endpoint checks that the single chosen suite is one it offered
+            let public_key_res = if cipher_suite.is_none() ||
+                (conn.initiator && message.cipher_suites.len() != 1) ||
+                message.ephemeral_key.is_some() != ecdh ||
+                message.nonce.as_ref().map_or(!ecdh, |nonce| ecdh || nonce.len() != KEY_LEN) {
+                // No mutually supported cipher suite or key exchange,
+                // this connection can never be secure
+                Err("Key exchange mismatch!".to_string())
+            } else {
+                self.remote_public_key(&conn, &message)
+            };
+            if let Ok(public_key) = public_key_res {
+                if ecdh && !self.verify_public_key_message(&conn, &message, public_key.as_slice()) {
+                    return;
+                }
+                if !self.check_host_key(&conn, public_key.as_slice()) {
+                    true
+                } else {
+                    if conn.set_public_key(public_key).is_err() {
+                        return;
+                    }
+                    if !ecdh && conn.get_public_key().unwrap().id() != Id::RSA {
+                        // Secret keys can only be encrypted with RSA keys
+                        true
+                    } else {
+                        *conn.cipher_suite.write().unwrap() = cipher_suite;
+                        *conn.remote_handshake_nonce.write().unwrap() = message.nonce.clone();
+                        *crypt_state = CryptState::Asymm;
+                        false
+                    }
+                }
+            } else {
+                true
+            }
+        };
+        if rejected {
+            self.disconnect(&conn.id);
+            return;
+        }
+        if !conn.initiator {
+            self.send_public_key(&conn, vec![cipher_suite.unwrap()], Some(&message)).unwrap_or(0);
+        }
+        if ecdh {
+            let remote_ephemeral_key = message.ephemeral_key.unwrap();
+            if self.derive_ecdh_secret(&conn, remote_ephemeral_key.as_slice()).is_ok() {
+                *conn.crypt_state.write().unwrap() = CryptState::Symm;
+            }
+        } else if conn.initiator {
+            self.send_secret_key(&conn).unwrap_or(0);
+        }
+    }
+
+    /**
+     * Internal function returning the remote public key (binary DER) of a  
+     * public key message. If it carries a certificate chain, the key is taken from  
+     * the leaf certificate after verifying the chain against the trusted CAs.  
+     * Remote endpoints must send a certificate chain if trusted CAs are configured.
+     */
+    fn remote_public_key(&self, conn: &ConnectionArc, message: &PublicKeyMessage) -> Result<Vec<u8>, Error> {
+        if message.certificates.is_empty() {
+            if self.config.trusted_ca.is_some() {
+                return Err("Remote endpoint did not send a certificate!".to_string());
+            }
+            return Ok(
+                message.public_key.clone()
+            );
+        }
+        let certificate = match self.config.trusted_ca {
+            Some(ref store) => verify_chain(
+                store,
+                message.certificates.as_slice(),
+                self.config.peer_name.as_ref()
+            )?,
+            None => {
+                let certificate_res = X509::from_der(message.certificates[0].as_slice());
+                if certificate_res.is_err() {
+                    return Err("Error decoding DER to certificate!".to_string());
+                }
+                certificate_res.unwrap()
+            }
+        };
+        let public_key_res = certificate.public_key()
+            .and_then(|public_key| public_key.public_key_to_der());
+        if public_key_res.is_err() {
+            return Err("Error extracting public key from certificate!".to_string());
+        }
+        *conn.certificate.write().unwrap() = Some(certificate);
+        Ok(
+            public_key_res.unwrap()
+        )
+    }
+
+    /**
+     * Internal function checking the public key of an endpoint we  
+     * connected to against the known hosts, if configured.  
+     * Returns false if the connection must be rejected.
+     */
+    pub(crate) fn check_host_key(&self, conn: &ConnectionArc, public_key_der: &[u8]) -> bool {
+        if !conn.initiator {
+            return true;
+        }
+        let status = {
+            let mut known_hosts = self.known_hosts.write().unwrap();
+            match known_hosts.as_mut() {
+                Some(known_hosts) => known_hosts.check(&conn.address, public_key_der),
+                None => HostKeyStatus::Unknown
+            }
+        };
+        *conn.host_key_status.write().unwrap() = status;
+        status != HostKeyStatus::Mismatch || self.config.host_key_policy == HostKeyPolicy::Report
+    }
+
+    /**
+     * Internal function verifying the transcript signature  
+     * of a `KeyExchange::Ecdh` public key message.
+     */
+    fn verify_public_key_message(&self, conn: &ConnectionArc, message: &PublicKeyMessage, public_key_der: &[u8]) -> bool {
+        let public_key_res = PKey::public_key_from_der(public_key_der);
+        if public_key_res.is_err() || message.signature.is_none() {
+            return false;
+        }
+        // The initiating endpoint needs its own offer to check the answer
+        let own_ephemeral_key = if conn.initiator {
+            let ephemeral_key = conn.ephemeral_key.read().unwrap();
+            match ephemeral_key.as_ref().map(x25519_public_bytes) {
+                Some(Ok(public)) => Some(public),
+                _ => return false
+            }
+        } else {
+            None
+        };
+        let transcript = PublicKeyMessage::transcript(
+            conn.id,
+            !conn.initiator,
+            message.ephemeral_key.as_ref().unwrap().as_slice(),
+            message.cipher_suites.as_slice(),
+            own_ephemeral_key.as_ref().map(|key| (
+                key.as_slice(),
+                self.config.cipher_suites.as_slice()
+            ))
+        );
+        verify(
+            &public_key_res.unwrap(),
+            transcript.as_slice(),
+            message.signature.as_ref().unwrap().as_slice()
+        )
+    }
+
+    /**
+     * Internal function deriving the secret key from our ephemeral key  
+     * and the remote ephemeral key. The ephemeral key is discarded afterwards.
+     */
+    fn derive_ecdh_secret(&self, conn: &ConnectionArc, remote_ephemeral_key: &[u8]) -> Result<(), Error> {
+        let ephemeral_key_opt = conn.ephemeral_key.write().unwrap().take();
+        if ephemeral_key_opt.is_none() {
+            return Err("No ephemeral key!".to_string());
+        }
+        let ephemeral_key = ephemeral_key_opt.unwrap();
+        let shared_secret = Secret::new(derive_x25519(&ephemeral_key, remote_ephemeral_key)?);
+        let own_ephemeral_key = x25519_public_bytes(&ephemeral_key)?;
+        // Both endpoints hash the ephemeral keys in the same order
+        let mut info = b"udps ecdh secret key".to_vec();
+        if conn.initiator {
+            info.extend_from_slice(own_ephemeral_key.as_slice());
+            info.extend_from_slice(remote_ephemeral_key);
+        } else {
+            info.extend_from_slice(remote_ephemeral_key);
+            info.extend_from_slice(own_ephemeral_key.as_slice());
+        }
+        let secret_key = hmac_sha256(shared_secret.as_bytes(), info.as_slice())?;
+        conn.set_secret(Secret::new(secret_key));
+        Ok(())
+    }
+
+    /**
+     * Internal function for generating the secret key and sending it  
+     * encrypted with the remote public key with the `SymmKey` method.
+     */
+    fn send_secret_key(&self, conn: &ConnectionArc) -> Result<usize, Error> {
+        let public_key_res = conn.get_public_key().unwrap().rsa();
+        if public_key_res.is_err() {
+            return Err("Remote public key is no RSA key!".to_string());
+        }
+        let public_key = public_key_res.unwrap();
+        let secret_key = Secret::random(KEY_LEN);
+        let mut encrypted: Vec<u8> = vec![0; public_key.size() as usize];
+        let encrypt_res = public_key.public_encrypt(
+            secret_key.as_bytes(),
+            encrypted.as_mut_slice(),
+            Padding::PKCS1_OAEP
+        );
+        if encrypt_res.is_err() {
+            return Err("Error encrypting secret key!".to_string());
+        }
+        encrypted.truncate(encrypt_res.unwrap());
+        // Prove that we hold the private key of the public key or certificate we sent
+        let private_key = self.config.identity_key()?;
+        let transcript = self.rsa_transcript(conn, &private_key, encrypted.as_slice())?;
+        let message = SecretKeyMessage {
+            signature: sign(&private_key, transcript.as_slice())?,
+            encrypted_key: encrypted
+        };
+        conn.set_secret(secret_key);
+        *conn.handshake_nonce.write().unwrap() = None;
+        *conn.remote_handshake_nonce.write().unwrap() = None;
+
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.header.method_type = MethodType::SymmKey;
+        package.header.ack = true;
+        package.data = message.try_into()?;
+        self.send(package)
+    }
+
+    /**
+     * Internal function building the transcript signed by the initiating endpoint  
+     * in `KeyExchange::Rsa` mode, with our identity key and the stored nonces.
+     */
+    fn rsa_transcript(&self, conn: &ConnectionArc, private_key: &PKey<Private>, encrypted_key: &[u8]) -> Result<Vec<u8>, Error> {
+        let own_public_key_res = private_key.public_key_to_der();
+        let remote_public_key_res = conn.get_public_key().unwrap().public_key_to_der();
+        if own_public_key_res.is_err() || remote_public_key_res.is_err() {
+            return Err("Error encoding public key to DER!".to_string());
+        }
+        let own_nonce = conn.handshake_nonce.read().unwrap().clone();
+        let remote_nonce = conn.remote_handshake_nonce.read().unwrap().clone();
+        if own_nonce.is_none() || remote_nonce.is_none() || conn.get_cipher_suite().is_none() {
+            return Err("Handshake state missing!".to_string());
+        }
+        let own = (own_public_key_res.unwrap(), own_nonce.unwrap());
+        let remote = (remote_public_key_res.unwrap(), remote_nonce.unwrap());
+        let (initiator, acceptor) = if conn.initiator { (own, remote) } else { (remote, own) };
+        Ok(
+            SecretKeyMessage::transcript(
+                conn.id,
+                initiator.0.as_slice(),
+                acceptor.0.as_slice(),
+                initiator.1.as_slice(),
+                acceptor.1.as_slice(),
+                conn.get_cipher_suite().unwrap(),
+                encrypted_key
+            )
+        )
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `SymmKey` method.  
+     * The secret key is decrypted with our private key and stored,  
+     * after which the connection is secure. If we sent a resumption ticket,  
+     * it answers our `Connect` package instead.
+     */
+    pub(crate) fn handle_symm_key(&self, conn: ConnectionArc, package: Package) {
+        if conn.initiator && conn.ticket.read().unwrap().is_some() {
+            self.handle_resume_answer(conn, package);
+            return;
+        }
+        if self.config.key_exchange == KeyExchange::Psk {
+            self.handle_psk_answer(conn, package);
+            return;
+        }
+        if self.config.key_exchange == KeyExchange::Noise {
+            self.handle_noise_message(conn, package);
+            return;
+        }
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        // Only the accepting endpoint receives a secret key, and only once
+        if conn.initiator ||
+            *crypt_state != CryptState::Asymm ||
+            self.config.key_exchange != KeyExchange::Rsa {
+            return;
+        }
+        let message_res = SecretKeyMessage::try_from(package.data);
+        if message_res.is_err() {
+            return;
+        }
+        let message = message_res.unwrap();
+        // The initiating endpoint must hold the private key of the public key or certificate it sent
+        let transcript_res = self.config.identity_key()
+            .and_then(|identity_key| self.rsa_transcript(&conn, &identity_key, message.encrypted_key.as_slice()));
+        if transcript_res.is_err() ||
+            !verify(&conn.get_public_key().unwrap(), transcript_res.unwrap().as_slice(), message.signature.as_slice()) {
+            conn.dropped_packages.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let private_key = self.config.private_key.as_ref().unwrap();
+        let mut decrypted: Vec<u8> = vec![0; private_key.size() as usize];
+        let decrypt_res = private_key.private_decrypt(
+            message.encrypted_key.as_slice(),
+            decrypted.as_mut_slice(),
+            Padding::PKCS1_OAEP
+        );
+        if decrypt_res.is_err() {
+            zeroize(decrypted.as_mut_slice());
+            return;
+        }
+        decrypted.truncate(decrypt_res.unwrap());
+        conn.set_secret(Secret::new(decrypted));
+        *conn.handshake_nonce.write().unwrap() = None;
+        *conn.remote_handshake_nonce.write().unwrap() = None;
+        *crypt_state = CryptState::Symm;
+    }
+
+    /**
+     * Internal function for handling the `SymmKey` package answering  
+     * our `Connect` package in `KeyExchange::Psk` mode.  
+     * The secret key is derived and the MAC of the accepting endpoint  
+     * checked, after which the connection is secure.
+     */
+    fn handle_psk_answer(&self, conn: ConnectionArc, package: Package) {
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        if !conn.initiator || *crypt_state != CryptState::None {
+            return;
+        }
+        let message_res = PskMessage::try_from(package.data);
+        let nonce_opt = conn.handshake_nonce.read().unwrap().clone();
+        if message_res.is_err() || nonce_opt.is_none() {
+            return;
+        }
+        let message = message_res.unwrap();
+        let own_nonce = nonce_opt.unwrap();
+        if !self.config.cipher_suites.contains(&message.cipher_suite) {
+            return;
+        }
+        let secret_res = PskMessage::derive_secret(
+            self.config.psk.as_ref().unwrap(),
+            self.config.psk_identity.as_ref().unwrap(),
+            &own_nonce,
+            &message.nonce
+        );
+        if secret_res.is_err() {
+            return;
+        }
+        let secret_key = secret_res.unwrap();
+        let mac_res = PskMessage::compute_mac(
+            &secret_key,
+            &own_nonce,
+            &message.nonce,
+            message.cipher_suite,
+            message.early_data_accepted
+        );
+        if mac_res.is_err() || !constant_time_eq(mac_res.unwrap().as_slice(), message.mac.as_slice()) {
+            // Forged answer or a different pre-shared key
+            return;
+        }
+        conn.set_secret(secret_key);
+        *conn.cipher_suite.write().unwrap() = Some(message.cipher_suite);
+        conn.early_data_accepted.store(message.early_data_accepted, Ordering::Relaxed);
+        *conn.handshake_nonce.write().unwrap() = None;
+        *crypt_state = CryptState::Symm;
+    }
+
+    /**
+     * Internal function answering the first Noise message, carried by a `Connect`  
+     * package in `KeyExchange::Noise` mode, with the second one in an `AsymmKey`  
+     * package. Its payload is the chosen cipher suite, the offered ones are part of  
+     * the prologue. With `NoisePattern::IK` the handshake is finished afterwards.
+     */
+    fn handle_noise_connect(&self, conn: ConnectionArc, package: Package) {
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        // Retransmitted `Connect` packages are already acknowledged, nothing else to do
+        if conn.initiator || *crypt_state != CryptState::None {
+            return;
+        }
+        let answer_res = ConnectMessage::try_from(package.data).and_then(|message| {
+            let cipher_suite = CipherSuite::negotiate(&message.cipher_suites, &self.config.cipher_suites);
+            if cipher_suite.is_none() || message.noise_pattern.is_none() || message.noise_message.is_none() {
+                return Err("No mutually supported cipher suite or key exchange!".to_string());
+            }
+            let mut handshake = NoiseHandshake::new(
+                message.noise_pattern.unwrap(),
+                false,
+                self.config.noise_static_key.clone().unwrap(),
+                None,
+                noise_prologue(conn.id, message.cipher_suites.as_slice()).as_slice()
+            )?;
+            handshake.read_message(message.noise_message.unwrap().as_slice())?;
+            let answer = handshake.write_message(&[cipher_suite.unwrap() as u8])?;
+            Ok(
+                (handshake, cipher_suite.unwrap(), answer)
+            )
+        });
+        if answer_res.is_err() {
+            drop(crypt_state);
+            self.disconnect(&conn.id);
+            return;
+        }
+        let (handshake, cipher_suite, answer) = answer_res.unwrap();
+        *conn.cipher_suite.write().unwrap() = Some(cipher_suite);
+        if handshake.is_finished() {
+            if self.finish_noise(&conn, handshake).is_err() {
+                drop(crypt_state);
+                self.disconnect(&conn.id);
+                return;
+            }
+            *crypt_state = CryptState::Symm;
+        } else {
+            *conn.noise.write().unwrap() = Some(handshake);
+            *crypt_state = CryptState::Asymm;
+        }
+        drop(crypt_state);
+
+        let mut response_package = Package::new_default();
+        response_package.header.connection_id = conn.id;
+        response_package.header.method_type = MethodType::AsymmKey;
+        response_package.header.ack = true;
+        response_package.data = answer;
+        self.send(response_package).unwrap_or(0);
+    }
+
+    /**
+     * Internal function reading the next Noise message in `KeyExchange::Noise` mode.  
+     * The initiating endpoint reads the answer to its `Connect` package from an `AsymmKey`  
+     * package and, with `NoisePattern::XX`, sends the last message with the `SymmKey` method,  
+     * which the accepting endpoint reads. The connection is secure once the handshake is finished.
+     */
+    fn handle_noise_message(&self, conn: ConnectionArc, package: Package) {
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        let expected = if conn.initiator {
+            package.header.method_type == MethodType::AsymmKey && *crypt_state == CryptState::None
+        } else {
+            package.header.method_type == MethodType::SymmKey && *crypt_state == CryptState::Asymm
+        };
+        let mut noise = conn.noise.write().unwrap();
+        if !expected || noise.is_none() {
+            return;
+        }
+        let payload_res = noise.as_mut().unwrap().read_message(package.data.as_slice());
+        if payload_res.is_err() {
+            // Forged or corrupted message, wait for the genuine one
+            return;
+        }
+        let payload = payload_res.unwrap();
+        let mut answer = None;
+        if conn.initiator {
+            // The accepting endpoint must choose one of the suites we offered
+            let cipher_suite = self.config.cipher_suites.iter()
+                .find(|suite| payload.len() == 1 && **suite as u8 == payload[0])
+                .cloned();
+            let remote_static_key = noise.as_ref().unwrap().remote_static_key().map(|key| key.to_vec());
+            let host_key_res = remote_static_key.ok_or("No remote static key!".to_string())
+                .and_then(|key| x25519_public_der(key.as_slice()));
+            if cipher_suite.is_none() ||
+                host_key_res.is_err() ||
+                !self.check_host_key(&conn, host_key_res.unwrap().as_slice()) {
+                drop(noise);
+                drop(crypt_state);
+                self.disconnect(&conn.id);
+                return;
+            }
+            *conn.cipher_suite.write().unwrap() = cipher_suite;
+            if !noise.as_ref().unwrap().is_finished() {
+                let answer_res = noise.as_mut().unwrap().write_message(&[]);
+                if answer_res.is_err() {
+                    return;
+                }
+                answer = Some(answer_res.unwrap());
+            }
+        }
+        let handshake = noise.take().unwrap();
+        drop(noise);
+        *conn.noise_message.write().unwrap() = None;
+        if self.finish_noise(&conn, handshake).is_err() {
+            drop(crypt_state);
+            self.disconnect(&conn.id);
+            return;
+        }
+        *crypt_state = CryptState::Symm;
+        drop(crypt_state);
+
+        if let Some(answer) = answer {
+            let mut response_package = Package::new_default();
+            response_package.header.connection_id = conn.id;
+            response_package.header.method_type = MethodType::SymmKey;
+            response_package.header.ack = true;
+            response_package.data = answer;
+            self.send(response_package).unwrap_or(0);
+        }
+    }
+
+    /**
+     * Internal function taking the secret key from a finished Noise handshake  
+     * and storing the static key of the remote endpoint as its public key.
+     */
+    fn finish_noise(&self, conn: &ConnectionArc, handshake: NoiseHandshake) -> Result<(), Error> {
+        let remote_static_key = handshake.remote_static_key();
+        if remote_static_key.is_none() {
+            return Err("No remote static key!".to_string());
+        }
+        conn.set_public_key(x25519_public_der(remote_static_key.unwrap())?)?;
+        conn.set_secret(handshake.split()?);
+        Ok(())
+    }
+
+    /**
+     * Internal function offering a new secret key with the `Rekey` method if  
+     * the current one exhausted its package, byte or time budget.  
+     * Only the initiating endpoint renews secret keys, and until the remote  
+     * endpoint acknowledges the new key we keep sealing with the current one.
+     */
+    pub(crate) fn check_rekey(&self, conn: &ConnectionArc) {
+        // DTLS sessions manage their own keys
+        if self.config.transport == Transport::Dtls ||
+            !conn.initiator ||
+            *conn.crypt_state.read().unwrap() != CryptState::Symm ||
+            conn.pending_secret.read().unwrap().is_some() {
+            return;
+        }
+        let packages_exhausted = match self.config.rekey_after_packages {
+            Some(max) => conn.packages_since_rekey.load(Ordering::Relaxed) >= max,
+            None => false
+        };
+        let bytes_exhausted = match self.config.rekey_after_bytes {
+            Some(max) => conn.bytes_since_rekey.load(Ordering::Relaxed) >= max,
+            None => false
+        };
+        let time_exhausted = match self.config.rekey_after_time {
+            Some(max) => conn.keyed_at.read().unwrap().elapsed() >= Duration::from_millis(max),
+            None => false
+        };
+        if !packages_exhausted && !bytes_exhausted && !time_exhausted {
+            return;
+        }
+        let secret_key = Secret::random(KEY_LEN);
+        let message = RekeyMessage {
+            epoch: conn.key_epoch.load(Ordering::Relaxed).wrapping_add(1),
+            secret_key: secret_key.as_bytes().to_vec()
+        };
+        if conn.offer_secret(message.epoch, secret_key).is_err() {
+            return;
+        }
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.header.method_type = MethodType::Rekey;
+        package.header.ack = true;
+        let data_res = message.try_into();
+        if data_res.is_err() {
+            *conn.pending_secret.write().unwrap() = None;
+            return;
+        }
+        package.data = data_res.unwrap();
+        self.send(package).unwrap_or(0);
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `Rekey` method.  
+     * The new secret key is used right away, the previous one is  
+     * kept for the configured overlap.
+     */
+    pub(crate) fn handle_rekey(&self, conn: ConnectionArc, package: Package) {
+        // Only accept new secret keys sealed with a secret key we already trust
+        if conn.initiator || package.header.crypt_type != CryptType::Symm {
+            return;
+        }
+        let message_res = RekeyMessage::try_from(package.data);
+        if message_res.is_err() {
+            return;
+        }
+        let mut message = message_res.unwrap();
+        if message.secret_key.len() != KEY_LEN {
+            return;
+        }
+        // Retransmitted `Rekey` packages whose acknowledgement got lost offer
+        // the current secret key again, they are acknowledged once more already.
+        // Another secret key for the current generation means we cannot agree on one anymore
+        let current_epoch = conn.key_epoch.load(Ordering::Relaxed);
+        if message.epoch == current_epoch {
+            let matches = match conn.get_secret_for_epoch(current_epoch) {
+                Some(secret_key) => constant_time_eq(secret_key.as_bytes(), message.secret_key.as_slice()),
+                None => false
+            };
+            if !matches {
+                self.disconnect(&conn.id);
+            }
+            return;
+        }
+        if message.epoch != current_epoch.wrapping_add(1) {
+            return;
+        }
+        conn.rotate_secret(
+            message.epoch,
+            Secret::new(std::mem::take(&mut message.secret_key)),
+            Duration::from_millis(self.config.rekey_overlap)
+        );
+    }
+
+    /**
+     * Internal function switching to the offered secret key  
+     * after the remote endpoint acknowledged it.
+     */
+    pub(crate) fn finish_rekey(&self, conn: &ConnectionArc) {
+        let pending_secret = conn.pending_secret.read().unwrap()
+            .as_ref()
+            .map(|(epoch, secret_key)| (*epoch, secret_key.duplicate()));
+        if pending_secret.is_none() {
+            return;
+        }
+        let (epoch, secret_key) = pending_secret.unwrap();
+        conn.rotate_secret(
+            epoch,
+            secret_key,
+            Duration::from_millis(self.config.rekey_overlap)
+        );
+        // Only forget the offered key now, so packages sealed with it can always be opened
+        *conn.pending_secret.write().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    fn wait<F: Fn() -> bool>(condition: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn config() -> EndpointConfig {
+        EndpointConfig::new(&"127.0.0.1:0".to_string())
+    }
+
+    fn connect(server_config: EndpointConfig, client_config: EndpointConfig) -> (EndpointArc, EndpointArc, ConnectionArc) {
+        let server = Endpoint::new(server_config).unwrap();
+        let client = Endpoint::new(client_config).unwrap();
+        let address = server.socket.local_addr().unwrap().to_string();
+        let conn = client.connect(&address).unwrap();
+        (server, client, conn)
+    }
+
+    fn accept(server: &EndpointArc) -> Option<ConnectionArc> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            let mut connections = server.collect_new_connections();
+            if !connections.is_empty() {
+                return Some(connections.remove(0));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn secure(conn: &ConnectionArc) -> bool {
+        wait(|| *conn.crypt_state.read().unwrap() == CryptState::Symm)
+    }
+
+    fn rekey_package(conn: &ConnectionArc, epoch: u32, secret_key: &[u8]) -> Package {
+        let message = RekeyMessage {
+            epoch: epoch,
+            secret_key: secret_key.to_vec()
+        };
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.header.method_type = MethodType::Rekey;
+        package.header.crypt_type = CryptType::Symm;
+        package.data = message.try_into().unwrap();
+        package
+    }
+
+    fn psk_config() -> EndpointConfig {
+        EndpointConfig::with_psk(&"127.0.0.1:0".to_string(), &"sensor".to_string(), vec![7; KEY_LEN])
+    }
+
+    fn ecdh_config() -> EndpointConfig {
+        let mut config = config();
+        config.key_exchange = KeyExchange::Ecdh;
+        config
+    }
+
+    /**
+     * Relays the datagrams between a client and the server, letting `tamper` modify  
+     * the packages first (true for those of the server). Returns the address  
+     * to connect to and the flag stopping the relay.
+     */
+    fn relay<F>(server: &EndpointArc, tamper: F) -> (String, Arc<AtomicBool>)
+        where F: Fn(bool, &mut Package) -> bool + Send + 'static {
+        let server_address = server.socket.local_addr().unwrap();
+        let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        proxy.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        relay.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let proxy_address = proxy.local_addr().unwrap().to_string();
+        let running = Arc::new(AtomicBool::new(true));
+        let relay_running = running.clone();
+        thread::spawn(move || {
+            let mut client_address = None;
+            let mut buffer = vec![0; 8192];
+            while relay_running.load(Ordering::Relaxed) {
+                for &from_server in [false, true].iter() {
+                    let socket = if from_server { &relay } else { &proxy };
+                    let (len, address) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        Err(_) => continue
+                    };
+                    let mut datagram = buffer[..len].to_vec();
+                    if let Ok(mut package) = Package::try_from(datagram.clone()) {
+                        if tamper(from_server, &mut package) {
+                            datagram = package.try_into().unwrap();
+                        }
+                    }
+                    if from_server {
+                        if let Some(address) = client_address {
+                            proxy.send_to(datagram.as_slice(), address).unwrap_or(0);
+                        }
+                    } else {
+                        client_address = Some(address);
+                        relay.send_to(datagram.as_slice(), server_address).unwrap_or(0);
+                    }
+                }
+            }
+        });
+        (proxy_address, running)
+    }
+
+    fn check_handshake(server_config: EndpointConfig, client_config: EndpointConfig) {
+        let (server, client, conn) = connect(server_config, client_config);
+        let server_conn = accept(&server).unwrap();
+        assert!(secure(&conn) && secure(&server_conn));
+        assert_eq!(conn.get_cipher_suite(), server_conn.get_cipher_suite());
+        send_data(&client, &conn);
+        send_data(&server, &server_conn);
+        assert!(wait(|| server_conn.collect_packages().iter().any(|package| package.data == b"data".to_vec())));
+        assert!(wait(|| conn.collect_packages().iter().any(|package| package.data == b"data".to_vec())));
+        client.stop();
+        server.stop();
+    }
+
+    /**
+     * Connects through a relay tampering with the data of the given handshake package,  
+     * sent by the server if `from_server` is true, and checks that the  
+     * endpoint receiving it never considers the connection secure.
+     */
+    fn check_tampered_handshake<F>(
+        server_config: EndpointConfig,
+        client_config: EndpointConfig,
+        from_server: bool,
+        method_type: MethodType,
+        tamper: F
+    ) where F: Fn(Vec<u8>) -> Vec<u8> + Send + 'static {
+        let server = Endpoint::new(server_config).unwrap();
+        let client = Endpoint::new(client_config).unwrap();
+        let (address, running) = relay(&server, move |sent_by_server, package| {
+            if sent_by_server != from_server || package.header.method_type != method_type {
+                return false;
+            }
+            package.data = tamper(package.data.clone());
+            true
+        });
+        let conn = client.connect(&address).unwrap();
+        let server_conn = accept(&server).unwrap();
+        let receiver = if from_server { conn } else { server_conn };
+        thread::sleep(Duration::from_secs(2));
+        assert!(*receiver.crypt_state.read().unwrap() != CryptState::Symm);
+        running.store(false, Ordering::Relaxed);
+        client.stop();
+        server.stop();
+    }
+
+    fn check_cipher_suite_mismatch(mut server_config: EndpointConfig, mut client_config: EndpointConfig) {
+        server_config.cipher_suites = vec![CipherSuite::Aes256Gcm];
+        client_config.cipher_suites = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Twofish256CtrHmacSha256];
+        let (server, client, conn) = connect(server_config, client_config);
+        assert!(wait(|| *conn.state.read().unwrap() == ConnectionState::Disconnected));
+        assert!(*conn.crypt_state.read().unwrap() != CryptState::Symm);
+        client.stop();
+        server.stop();
+    }
+
+    #[test]
+    fn rsa_handshake() {
+        check_handshake(config(), config());
+    }
+
+    #[test]
+    fn rsa_handshake_rejects_bad_signature() {
+        check_tampered_handshake(config(), config(), false, MethodType::SymmKey, |data| {
+            let mut message = SecretKeyMessage::try_from(data).unwrap();
+            message.signature[0] ^= 1;
+            message.try_into().unwrap()
+        });
+    }
+
+    #[test]
+    fn rsa_cipher_suite_mismatch() {
+        check_cipher_suite_mismatch(config(), config());
+    }
+
+    #[test]
+    fn ecdh_handshake() {
+        check_handshake(ecdh_config(), ecdh_config());
+    }
+
+    #[test]
+    fn ecdh_handshake_rejects_bad_signature() {
+        check_tampered_handshake(ecdh_config(), ecdh_config(), true, MethodType::AsymmKey, |data| {
+            let mut message = PublicKeyMessage::try_from(data).unwrap();
+            message.signature.as_mut().unwrap()[0] ^= 1;
+            message.try_into().unwrap()
+        });
+    }
+
+    #[test]
+    fn ecdh_cipher_suite_mismatch() {
+        check_cipher_suite_mismatch(ecdh_config(), ecdh_config());
+    }
+
+    #[test]
+    fn psk_handshake() {
+        check_handshake(psk_config(), psk_config());
+    }
+
+    #[test]
+    fn psk_handshake_rejects_bad_mac() {
+        check_tampered_handshake(psk_config(), psk_config(), true, MethodType::SymmKey, |data| {
+            let mut message = PskMessage::try_from(data).unwrap();
+            message.mac[0] ^= 1;
+            message.try_into().unwrap()
+        });
+    }
+
+    #[test]
+    fn psk_cipher_suite_mismatch() {
+        check_cipher_suite_mismatch(psk_config(), psk_config());
+    }
+
+    #[test]
+    fn requires_cipher_suites() {
+        let mut config = config();
+        config.cipher_suites = Vec::new();
+        assert!(Endpoint::new(config).is_err());
+    }
+
+    fn send_data(endpoint: &EndpointArc, conn: &ConnectionArc) {
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.data = b"data".to_vec();
+        endpoint.send(package).unwrap();
+    }
+
+    #[test]
+    fn reconnecting_client_replaces_connection() {
+        let key = generate_random_bytes(KEY_LEN);
+        let mut server_config = config();
+        server_config.connect_token_key = Some(Secret::new(key.clone()));
+        let server = Endpoint::new(server_config).unwrap();
+        let address = server.socket.local_addr().unwrap().to_string();
+        let token = ConnectToken::new(42, vec![address.clone()], Vec::new(), Duration::from_secs(60));
+        let sealed = token.seal(key.as_slice()).unwrap();
+        let connect = |client_key: Secret| {
+            let client = Endpoint::new(config()).unwrap();
+            let conn = client.connect_with_token(&address, sealed.clone(), client_key).unwrap();
+            let server_conn = accept(&server).unwrap();
+            assert!(secure(&conn) && secure(&server_conn));
+            send_data(&client, &conn);
+            (client, server_conn)
+        };
+        let connected = |conn: &ConnectionArc| server.collect_connections().iter().any(|other| other.id == conn.id);
+        let (first, first_conn) = connect(Secret::new(token.client_key.clone()));
+        assert!(wait(|| first_conn.connect_token_proven.load(Ordering::Relaxed)));
+        // Replaying the token without its client key does not lock the client out, nor replace it
+        let (thief, thief_conn) = connect(Secret::random(KEY_LEN));
+        assert!(wait(|| thief_conn.dropped_packages.load(Ordering::Relaxed) > 0));
+        assert!(connected(&first_conn) && !thief_conn.connect_token_proven.load(Ordering::Relaxed));
+        // The client connecting again replaces its earlier connection
+        let (second, second_conn) = connect(Secret::new(token.client_key.clone()));
+        assert!(wait(|| !connected(&first_conn)));
+        assert!(connected(&second_conn));
+        first.stop();
+        thief.stop();
+        second.stop();
+        server.stop();
+    }
+
+    #[test]
+    fn retransmitted_rekey_keeps_secret_key() {
+        let (server, client, conn) = connect(config(), config());
+        let server_conn = accept(&server).unwrap();
+        assert!(secure(&conn) && secure(&server_conn));
+        let secret_key = generate_random_bytes(KEY_LEN);
+        server.handle_rekey(server_conn.clone(), rekey_package(&server_conn, 1, secret_key.as_slice()));
+        assert_eq!(server_conn.key_epoch.load(Ordering::Relaxed), 1);
+        // The acknowledgement got lost and the same secret key is offered again
+        server.handle_rekey(server_conn.clone(), rekey_package(&server_conn, 1, secret_key.as_slice()));
+        assert_eq!(server_conn.key_epoch.load(Ordering::Relaxed), 1);
+        assert_eq!(server_conn.get_secret().as_bytes(), secret_key.as_slice());
+        assert!(*server_conn.state.read().unwrap() != ConnectionState::Disconnected);
+        // Another secret key for the same generation cannot be agreed on anymore
+        server.handle_rekey(server_conn.clone(), rekey_package(&server_conn, 1, generate_random_bytes(KEY_LEN).as_slice()));
+        assert_eq!(server_conn.get_secret().as_bytes(), secret_key.as_slice());
+        assert!(*server_conn.state.read().unwrap() == ConnectionState::Disconnected);
+        client.stop();
+        server.stop();
+    }
+
+    #[test]
+    fn lost_rekey_acks_disconnect() {
+        let mut client_config = config();
+        client_config.rekey_after_packages = Some(1);
+        client_config.ack_loop_time = 20;
+        client_config.max_ack_attempts = 3;
+        let (server, client, conn) = connect(config(), client_config);
+        let server_conn = accept(&server).unwrap();
+        assert!(secure(&conn) && secure(&server_conn));
+        // Nothing acknowledges the `Rekey` package anymore, without the server saying goodbye
+        server.running.store(false, Ordering::Relaxed);
+        // Its receive loop may still handle one more package, keep using the secret key up
+        assert!(wait(|| {
+            let mut package = Package::new_default();
+            package.header.connection_id = conn.id;
+            package.data = b"rekey".to_vec();
+            client.send(package).unwrap_or(0);
+            *conn.state.read().unwrap() == ConnectionState::Disconnected
+        }));
+        // The offered secret key was not given up on for another one
+        assert!(conn.pending_secret.read().unwrap().is_some());
+        assert!(client.collect_connections().is_empty());
+        client.stop();
+    }
+
+    #[test]
+    fn forged_resume_rejection_keeps_ticket() {
+        let (server, client, conn) = connect(config(), config());
+        let server_conn = accept(&server).unwrap();
+        assert!(secure(&conn) && secure(&server_conn));
+        let address = server.socket.local_addr().unwrap().to_string();
+        assert!(wait(|| client.tickets.read().unwrap().contains_key(&address)));
+        // The server does not answer anymore, so only the forged answer arrives
+        server.running.store(false, Ordering::Relaxed);
+        client.disconnect(&conn.id);
+        let resumed_conn = client.connect(&address).unwrap();
+        assert!(resumed_conn.ticket.read().unwrap().is_some());
+        let message = ResumeMessage {
+            accepted: false,
+            early_data_accepted: false,
+            nonce: vec![],
+            mac: vec![]
+        };
+        let mut package = Package::new_default();
+        package.header.connection_id = resumed_conn.id;
+        package.header.method_type = MethodType::SymmKey;
+        package.data = message.try_into().unwrap();
+        client.handle_symm_key(resumed_conn.clone(), package);
+        // The connection falls back to the full handshake, the stored ticket is kept
+        assert!(resumed_conn.ticket.read().unwrap().is_none());
+        assert!(client.tickets.read().unwrap().contains_key(&address));
+        client.stop();
+    }
+}
@@ -0,0 +1,274 @@
+use std::convert::*;
+use std::vec::*;
+
+use openssl::symm;
+use rand::prelude::*;
+use rmps::*;
+
+use crate::prelude::*;
+
+/**
+ * Length of the random salt following the connection id of a protected datagram
+ */
+pub const HP_SALT_LEN: usize = 16;
+
+/**
+ * Length of the connection id and salt preceding the masked bytes
+ */
+const HP_PREFIX_LEN: usize = 4 + HP_SALT_LEN;
+
+/**
+ * Length of the masked header and payload lengths
+ */
+const HP_LENGTHS_LEN: usize = 6;
+
+/**
+ * Salt deriving the initial key from the connection id
+ */
+const HP_INITIAL_SALT: &[u8] = b"udps initial header protection";
+
+/**
+ * Key masking the headers of the packages of a connection, like QUIC header protection.  
+ * A protected datagram is made of the connection id, a random salt, the masked lengths,  
+ * header and payload, and random padding. Sealed payloads are not masked again,  
+ * so all bytes after the connection id look uniformly random.
+ */
+pub struct HeaderProtection {
+    key: Secret,
+}
+
+impl HeaderProtection {
+    /**
+     * Derives the initial key of a connection, used until a secret key is exchanged.  
+     * Anybody knowing the connection id can derive it, it only hides the protocol.
+     */
+    pub fn initial(connection_id: u32) -> Result<Self, Error> {
+        let key = hmac_sha256(HP_INITIAL_SALT, &conv_u32_to_bytes(&connection_id))?;
+        Ok(
+            Self {
+                key: Secret::new(key)
+            }
+        )
+    }
+
+    /**
+     * Derives the session key of a connection from its exporter secret,  
+     * which stays the same when the secret key is renewed.
+     */
+    pub fn from_exporter_secret(exporter_secret: &Secret) -> Result<Self, Error> {
+        let key = hkdf_expand(exporter_secret.as_bytes(), b"udps header protection", KEY_LEN)?;
+        Ok(
+            Self {
+                key: Secret::new(key)
+            }
+        )
+    }
+
+    /**
+     * Returns the connection id of a protected datagram.
+     */
+    pub fn connection_id(datagram: &[u8]) -> Option<u32> {
+        if datagram.len() < HP_PREFIX_LEN + HP_LENGTHS_LEN {
+            return None;
+        }
+        Some(conv_slice_to_u32(datagram))
+    }
+
+    /**
+     * Encodes a package into a protected datagram, followed by `padding` random bytes.
+     */
+    pub fn protect(&self, package: &Package, padding: usize) -> Result<Vec<u8>, Error> {
+        let header = package.header.associated_data()?;
+        if header.len() > u16::max_value() as usize || package.data.len() > u32::max_value() as usize {
+            return Err("Package too large to protect!".to_string());
+        }
+        let salt = generate_random_bytes(HP_SALT_LEN);
+        let mut masked = (header.len() as u16).to_le_bytes().to_vec();
+        masked.extend_from_slice(&(package.data.len() as u32).to_le_bytes());
+        masked.extend_from_slice(header.as_slice());
+        let mask_payload = package.header.crypt_type != CryptType::Symm;
+        if mask_payload {
+            masked.extend_from_slice(package.data.as_slice());
+        }
+        self.apply_mask(salt.as_slice(), masked.as_mut_slice())?;
+
+        let mut datagram = conv_u32_to_bytes(&package.header.connection_id).to_vec();
+        datagram.extend_from_slice(salt.as_slice());
+        datagram.append(&mut masked);
+        if !mask_payload {
+            datagram.extend_from_slice(package.data.as_slice());
+        }
+        datagram.append(&mut generate_random_bytes(padding));
+        Ok(
+            datagram
+        )
+    }
+
+    /**
+     * Decodes a protected datagram into a package, ignoring its padding.  
+     * Fails if it was not masked with this key.
+     */
+    pub fn unprotect(&self, datagram: &[u8]) -> Result<Package, Error> {
+        let connection_id = Self::connection_id(datagram);
+        if connection_id.is_none() {
+            return Err("Protected datagram too short!".to_string());
+        }
+        let salt = &datagram[4..HP_PREFIX_LEN];
+        let mut unmasked = datagram[HP_PREFIX_LEN..].to_vec();
+        self.apply_mask(salt, unmasked.as_mut_slice())?;
+        let header_len = u16::from_le_bytes([unmasked[0], unmasked[1]]) as usize;
+        let data_len = conv_slice_to_u32(&unmasked[2..HP_LENGTHS_LEN]) as usize;
+        let header_end = HP_LENGTHS_LEN + header_len;
+        if header_end + data_len > unmasked.len() {
+            return Err("Invalid protected datagram lengths!".to_string());
+        }
+        let header_res = from_slice::<Header>(&unmasked[HP_LENGTHS_LEN..header_end]);
+        if header_res.is_err() {
+            return Err("Unknown error decoding MessagePack header!".to_string());
+        }
+        let header = header_res.unwrap();
+        if header.connection_id != connection_id.unwrap() {
+            return Err("Protected datagram with wrong connection id!".to_string());
+        }
+        // Sealed payloads were not masked
+        let data = if header.crypt_type == CryptType::Symm {
+            datagram[HP_PREFIX_LEN + header_end..HP_PREFIX_LEN + header_end + data_len].to_vec()
+        } else {
+            unmasked[header_end..header_end + data_len].to_vec()
+        };
+        Ok(
+            Package {
+                header: header,
+                data: data
+            }
+        )
+    }
+
+    /**
+     * XORs bytes with the AES-256-CTR keystream of the salt.
+     */
+    fn apply_mask(&self, salt: &[u8], bytes: &mut [u8]) -> Result<(), Error> {
+        let zeros = vec![0u8; bytes.len()];
+        let mask_res = symm::encrypt(symm::Cipher::aes_256_ctr(), self.key.as_bytes(), Some(salt), zeros.as_slice());
+        if mask_res.is_err() {
+            return Err("Error deriving header protection mask!".to_string());
+        }
+        for (byte, mask_byte) in bytes.iter_mut().zip(mask_res.unwrap().iter()) {
+            *byte ^= mask_byte;
+        }
+        Ok(())
+    }
+}
+
+impl Endpoint {
+    /**
+     * Internal function encoding a package to be sent over `Transport::Udps`,  
+     * protected and padded if `header_protection` is enabled. Handshake packages  
+     * are masked with the initial key, as the remote endpoint may not hold  
+     * the secret key yet, all others with the session key once we do.
+     */
+    pub(crate) fn encode_package(&self, connection: Option<&ConnectionArc>, package: Package) -> Result<Vec<u8>, Error> {
+        if !self.config.header_protection {
+            return package.try_into();
+        }
+        let session = connection
+            .filter(|conn| *conn.crypt_state.read().unwrap() == CryptState::Symm)
+            .filter(|_| match package.header.method_type {
+                MethodType::Connect | MethodType::AsymmKey | MethodType::SymmKey | MethodType::Cookie => false,
+                _ => true
+            })
+            .and_then(|conn| conn.exporter_secret.read().unwrap().as_ref().map(HeaderProtection::from_exporter_secret));
+        let protection = match session {
+            Some(protection_res) => protection_res?,
+            None => HeaderProtection::initial(package.header.connection_id)?
+        };
+        let padding = thread_rng().gen_range(0, self.config.max_datagram_padding.saturating_add(1));
+        protection.protect(&package, padding)
+    }
+
+    /**
+     * Internal function decoding a datagram received over `Transport::Udps`.  
+     * With `header_protection`, the session key of a known connection is tried  
+     * before the initial key.
+     */
+    pub(crate) fn decode_package(&self, datagram: Vec<u8>) -> Result<Package, Error> {
+        if !self.config.header_protection {
+            return Package::try_from(datagram);
+        }
+        let connection_id = HeaderProtection::connection_id(datagram.as_slice());
+        if connection_id.is_none() {
+            return Err("Protected datagram too short!".to_string());
+        }
+        let connection = {
+            let connections = self.connection_list.read().unwrap();
+            connections.get(&connection_id.unwrap()).cloned()
+        };
+        if let Some(conn) = connection {
+            if let Some(ref exporter_secret) = *conn.exporter_secret.read().unwrap() {
+                let package_res = HeaderProtection::from_exporter_secret(exporter_secret)
+                    .and_then(|protection| protection.unprotect(datagram.as_slice()));
+                if package_res.is_ok() {
+                    return package_res;
+                }
+            }
+        }
+        HeaderProtection::initial(connection_id.unwrap())?.unprotect(datagram.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(crypt_type: CryptType, data: Vec<u8>) -> Package {
+        let mut package = Package::new_default();
+        package.header.connection_id = 42;
+        package.header.method_type = MethodType::Data;
+        package.header.crypt_type = crypt_type;
+        package.data = data;
+        package
+    }
+
+    #[test]
+    fn round_trips_packages() {
+        let protection = HeaderProtection::initial(42).unwrap();
+        for crypt_type in vec![CryptType::None, CryptType::Auth, CryptType::Symm] {
+            let datagram = protection.protect(&package(crypt_type.clone(), vec![1, 2, 3, 4]), 16).unwrap();
+            assert_eq!(HeaderProtection::connection_id(datagram.as_slice()), Some(42));
+            let unprotected = protection.unprotect(datagram.as_slice()).unwrap();
+            assert_eq!(unprotected.header.connection_id, 42);
+            assert!(unprotected.header.crypt_type == crypt_type);
+            assert_eq!(unprotected.data, vec![1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn masks_unsealed_payloads_only() {
+        let protection = HeaderProtection::initial(42).unwrap();
+        let payload = b"plain text payload".to_vec();
+        let datagram = protection.protect(&package(CryptType::None, payload.clone()), 0).unwrap();
+        assert!(!datagram.windows(payload.len()).any(|window| window == payload.as_slice()));
+        // Sealed payloads look random already and follow the masked header as they are
+        let datagram = protection.protect(&package(CryptType::Symm, payload.clone()), 0).unwrap();
+        assert!(datagram.ends_with(payload.as_slice()));
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let session = HeaderProtection::from_exporter_secret(&Secret::new(vec![7; KEY_LEN])).unwrap();
+        let datagram = session.protect(&package(CryptType::None, vec![1, 2, 3]), 0).unwrap();
+        assert!(HeaderProtection::initial(42).unwrap().unprotect(datagram.as_slice()).is_err());
+        let other = HeaderProtection::from_exporter_secret(&Secret::new(vec![8; KEY_LEN])).unwrap();
+        assert!(other.unprotect(datagram.as_slice()).is_err());
+        assert!(session.unprotect(datagram.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn rejects_short_and_rerouted_datagrams() {
+        let protection = HeaderProtection::initial(42).unwrap();
+        assert!(protection.unprotect(&[0; HP_PREFIX_LEN]).is_err());
+        let mut datagram = protection.protect(&package(CryptType::None, vec![1, 2, 3]), 0).unwrap();
+        datagram[..4].copy_from_slice(&conv_u32_to_bytes(&43));
+        assert!(protection.unprotect(datagram.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::prelude::*;
+
+/**
+ * What to do if the public key of a known host changed
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostKeyPolicy {
+    /**
+     * Disconnect immediately
+     */
+    Reject = 0,
+    /**
+     * Continue, but report the mismatch in `Connection::host_key_status`
+     */
+    Report,
+}
+
+/**
+ * Result of checking a public key against the known hosts
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostKeyStatus {
+    /**
+     * Not checked (yet)
+     */
+    Unknown = 0,
+    /**
+     * First connection to this host, the key has been recorded
+     */
+    New,
+    /**
+     * The key matches the recorded one
+     */
+    Trusted,
+    /**
+     * The key differs from the recorded one!
+     */
+    Mismatch,
+}
+
+/**
+ * Trust-on-first-use store of public key fingerprints per remote address.  
+ * The file contains one `address fingerprint` pair per line,  
+ * lines starting with `#` are ignored.
+ */
+pub struct KnownHosts {
+    /**
+     * File the known hosts are persisted to
+     */
+    pub path: String,
+    /**
+     * Fingerprints by remote address
+     */
+    pub hosts: HashMap<String, String>,
+}
+
+impl KnownHosts {
+    /**
+     * Loads the known hosts from a file, starting out  
+     * empty if the file does not exist yet.
+     */
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut hosts = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                for line in content.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let mut fields = line.split_whitespace();
+                    let address = fields.next();
+                    let fingerprint = fields.next();
+                    if address.is_none() || fingerprint.is_none() {
+                        return Err(format!("Malformed line in known hosts file {} !", path));
+                    }
+                    hosts.insert(address.unwrap().to_string(), fingerprint.unwrap().to_string());
+                }
+            },
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {},
+            Err(_) => {
+                return Err(format!("Could not read known hosts file {} !", path));
+            }
+        };
+        Ok(
+            Self {
+                path: path.to_string(),
+                hosts: hosts
+            }
+        )
+    }
+
+    /**
+     * Writes the known hosts back to the file.
+     */
+    pub fn save(&self) -> Result<(), String> {
+        let mut addresses: Vec<&String> = self.hosts.keys().collect();
+        addresses.sort();
+        let content: String = addresses.iter()
+            .map(|address| format!("{} {}\n", address, self.hosts[*address]))
+            .collect();
+        if fs::write(&self.path, content).is_err() {
+            return Err(format!("Could not write known hosts file {} !", self.path));
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks the fingerprint of a public key (binary DER) received from an address,  
+     * recording and saving it if the address is not known yet.
+     */
+    pub fn check(&mut self, address: &String, public_key_der: &[u8]) -> HostKeyStatus {
+        let key_fingerprint = fingerprint(public_key_der);
+        let status = match self.hosts.get(address) {
+            Some(known) if *known == key_fingerprint => HostKeyStatus::Trusted,
+            Some(_) => HostKeyStatus::Mismatch,
+            None => HostKeyStatus::New
+        };
+        if status == HostKeyStatus::New {
+            self.hosts.insert(address.clone(), key_fingerprint);
+            self.save().unwrap_or(());
+        }
+        status
+    }
+
+    /**
+     * Forgets the recorded key of an address, e.g. after  
+     * its key was deliberately changed, and saves the file.
+     */
+    pub fn remove(&mut self, address: &String) -> Result<(), String> {
+        self.hosts.remove(address);
+        self.save()
+    }
+}
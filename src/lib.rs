@@ -18,6 +18,20 @@ pub mod prelude {
     pub use crate::connection::*;
     pub use crate::endpoint::*;
     pub use crate::package::*;
+    pub use crate::crypto::*;
+    pub use crate::handshake::*;
+    pub use crate::known_hosts::*;
+    pub use crate::certificate::*;
+    pub use crate::replay::*;
+    pub use crate::cookie::*;
+    pub use crate::connect_token::*;
+    pub use crate::secret::*;
+    pub use crate::noise::*;
+    pub use crate::dtls::*;
+    pub use crate::ticket::*;
+    pub use crate::early_data::*;
+    pub use crate::header_protection::*;
+    pub use crate::padding::*;
     pub use crate::util::*;
 }
 
@@ -42,6 +56,76 @@ pub mod connection;
  */
 pub mod package;
 
+/**
+ * Cryptographic primitives used to secure packages
+ */
+pub mod crypto;
+
+/**
+ * Messages exchanged during the handshake
+ */
+pub mod handshake;
+
+/**
+ * Trust-on-first-use store of remote public keys
+ */
+pub mod known_hosts;
+
+/**
+ * X.509 certificate loading and verification
+ */
+pub mod certificate;
+
+/**
+ * Anti-replay protection for sealed packages
+ */
+pub mod replay;
+
+/**
+ * Stateless cookies proving that a remote endpoint owns its address
+ */
+pub mod cookie;
+
+/**
+ * Expiring tokens authorizing clients to connect
+ */
+pub mod connect_token;
+
+/**
+ * Key material which is wiped from memory when dropped
+ */
+pub mod secret;
+
+/**
+ * Handshake state of the Noise protocol framework
+ */
+pub mod noise;
+
+/**
+ * DTLS transport via openssl
+ */
+pub mod dtls;
+
+/**
+ * Session resumption tickets
+ */
+pub mod ticket;
+
+/**
+ * Application data sent with the connection request
+ */
+pub mod early_data;
+
+/**
+ * Masking of package headers
+ */
+pub mod header_protection;
+
+/**
+ * Length-hiding padding of payloads
+ */
+pub mod padding;
+
 /**
  * C API function module
  */
@@ -54,11 +138,11 @@ pub static VERSION_MAJOR: u8 = 0;
 /**
  * Minor version constant
  */
-pub static VERSION_MINOR: u8 = 4;
+pub static VERSION_MINOR: u8 = 5;
 /**
  * Patch version constant
  */
-pub static VERSION_PATCH: u8 = 1;
+pub static VERSION_PATCH: u8 = 0;
 /**
  * Version constant
  */
@@ -0,0 +1,541 @@
+use std::vec::*;
+
+use openssl::hash::{
+    hash,
+    MessageDigest
+};
+use openssl::pkey::*;
+
+use crate::prelude::*;
+
+/**
+ * Length of a Curve25519 public key and of a SHA-256 hash in the Noise framework
+ */
+pub const NOISE_KEY_LEN: usize = 32;
+
+/**
+ * Noise handshake pattern used in `KeyExchange::Noise` mode
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum NoisePattern {
+    /**
+     * Both endpoints send their static key during the handshake,  
+     * which takes one and a half round trips
+     */
+    XX = 0,
+    /**
+     * The initiating endpoint knows the static key of the remote endpoint  
+     * beforehand, and the handshake takes a single round trip
+     */
+    IK,
+}
+
+/**
+ * Tokens of the Noise message patterns
+ */
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+static XX_MESSAGES: [&[Token]; 3] = [
+    &[Token::E],
+    &[Token::E, Token::EE, Token::S, Token::ES],
+    &[Token::S, Token::SE],
+];
+
+static IK_MESSAGES: [&[Token]; 2] = [
+    &[Token::E, Token::ES, Token::S, Token::SS],
+    &[Token::E, Token::EE, Token::SE],
+];
+
+impl NoisePattern {
+    /**
+     * Returns the full Noise protocol name.
+     */
+    pub fn protocol_name(&self) -> &'static str {
+        match *self {
+            NoisePattern::XX => "Noise_XX_25519_ChaChaPoly_SHA256",
+            NoisePattern::IK => "Noise_IK_25519_ChaChaPoly_SHA256",
+        }
+    }
+
+    /**
+     * Internal function returning the tokens of every handshake message,  
+     * messages with an even index are sent by the initiating endpoint.
+     */
+    fn messages(&self) -> &'static [&'static [Token]] {
+        match *self {
+            NoisePattern::XX => &XX_MESSAGES,
+            NoisePattern::IK => &IK_MESSAGES,
+        }
+    }
+}
+
+/**
+ * Handshake state of the Noise protocol framework (revision 34),  
+ * with Curve25519, ChaChaPoly and SHA-256.  
+ * Both endpoints write and read the messages of the pattern in turn,  
+ * after the last one the secret key is taken from `split`.
+ */
+pub struct NoiseHandshake {
+    /**
+     * Handshake pattern
+     */
+    pub pattern: NoisePattern,
+    /**
+     * True for the endpoint sending the first message
+     */
+    pub initiator: bool,
+    s: PKey<Private>,
+    e: Option<PKey<Private>>,
+    rs: Option<Vec<u8>>,
+    re: Option<Vec<u8>>,
+    ck: Secret,
+    h: Vec<u8>,
+    k: Option<Secret>,
+    n: u64,
+    message_index: usize,
+}
+
+impl NoiseHandshake {
+    /**
+     * Creates a new handshake state with our static X25519 key.  
+     * The static key of the remote endpoint must be given for `NoisePattern::IK`  
+     * on the initiating endpoint. The prologue is hashed into the handshake,  
+     * so both endpoints must use the same one.
+     */
+    pub fn new(
+        pattern: NoisePattern,
+        initiator: bool,
+        static_key: PKey<Private>,
+        remote_static_key: Option<Vec<u8>>,
+        prologue: &[u8]
+    ) -> Result<Self, String> {
+        let protocol_name = pattern.protocol_name().as_bytes();
+        let mut h = protocol_name.to_vec();
+        if h.len() > NOISE_KEY_LEN {
+            h = sha256(protocol_name)?;
+        } else {
+            h.resize(NOISE_KEY_LEN, 0);
+        }
+        let mut handshake = Self {
+            pattern: pattern,
+            initiator: initiator,
+            s: static_key,
+            e: None,
+            rs: None,
+            re: None,
+            ck: Secret::new(h.clone()),
+            h: h,
+            k: None,
+            n: 0,
+            message_index: 0,
+        };
+        handshake.mix_hash(prologue)?;
+        // Pre-message of `IK`: the static key of the responder is known to both
+        if pattern == NoisePattern::IK {
+            let responder_static = if initiator {
+                if remote_static_key.as_ref().map(|key| key.len()) != Some(NOISE_KEY_LEN) {
+                    return Err("The remote static key is required!".to_string());
+                }
+                handshake.rs = remote_static_key;
+                handshake.rs.clone().unwrap()
+            } else {
+                x25519_public_bytes(&handshake.s)?
+            };
+            handshake.mix_hash(responder_static.as_slice())?;
+        }
+        Ok(
+            handshake
+        )
+    }
+
+    /**
+     * Returns true if all messages of the pattern have been written or read.
+     */
+    pub fn is_finished(&self) -> bool {
+        self.message_index >= self.pattern.messages().len()
+    }
+
+    /**
+     * Returns true if the next message is ours to write.
+     */
+    pub fn is_our_turn(&self) -> bool {
+        !self.is_finished() && (self.message_index % 2 == 0) == self.initiator
+    }
+
+    /**
+     * Returns the raw static key of the remote endpoint, once it is known.
+     */
+    pub fn remote_static_key(&self) -> Option<&[u8]> {
+        self.rs.as_deref()
+    }
+
+    /**
+     * Returns the handshake hash, which is unique to this handshake  
+     * and can be used for channel binding.
+     */
+    pub fn handshake_hash(&self) -> &[u8] {
+        self.h.as_slice()
+    }
+
+    /**
+     * Writes the next handshake message with an optional payload,  
+     * which is encrypted once a shared key is established.
+     */
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        if !self.is_our_turn() {
+            return Err("Not our turn to write a handshake message!".to_string());
+        }
+        let mut message = Vec::new();
+        for token in self.pattern.messages()[self.message_index].iter() {
+            match *token {
+                Token::E => {
+                    // Only test vectors set the ephemeral key beforehand
+                    let e = match self.e.take() {
+                        Some(e) => e,
+                        None => generate_x25519()?
+                    };
+                    let e_public = x25519_public_bytes(&e)?;
+                    self.mix_hash(e_public.as_slice())?;
+                    message.extend_from_slice(e_public.as_slice());
+                    self.e = Some(e);
+                },
+                Token::S => {
+                    let s_public = x25519_public_bytes(&self.s)?;
+                    let encrypted = self.encrypt_and_hash(s_public.as_slice())?;
+                    message.extend_from_slice(encrypted.as_slice());
+                },
+                dh_token => self.mix_dh(dh_token)?
+            }
+        }
+        let encrypted = self.encrypt_and_hash(payload)?;
+        message.extend_from_slice(encrypted.as_slice());
+        self.message_index += 1;
+        Ok(
+            message
+        )
+    }
+
+    /**
+     * Reads the next handshake message of the remote endpoint  
+     * and returns its payload.
+     */
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, String> {
+        if self.is_finished() || self.is_our_turn() {
+            return Err("Not expecting a handshake message!".to_string());
+        }
+        // Forged messages must not spoil the handshake for the genuine one
+        let rs = self.rs.clone();
+        let re = self.re.clone();
+        let ck = self.ck.duplicate();
+        let h = self.h.clone();
+        let k = self.k.as_ref().map(|k| k.duplicate());
+        let n = self.n;
+        let payload_res = self.read_tokens(message);
+        if payload_res.is_err() {
+            self.rs = rs;
+            self.re = re;
+            self.ck = ck;
+            self.h = h;
+            self.k = k;
+            self.n = n;
+            return payload_res;
+        }
+        self.message_index += 1;
+        payload_res
+    }
+
+    /**
+     * Internal function processing the tokens and payload of a handshake message.
+     */
+    fn read_tokens(&mut self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let mut rest = message;
+        for token in self.pattern.messages()[self.message_index].iter() {
+            match *token {
+                Token::E => {
+                    if rest.len() < NOISE_KEY_LEN {
+                        return Err("Handshake message is too short!".to_string());
+                    }
+                    let (re, tail) = rest.split_at(NOISE_KEY_LEN);
+                    self.mix_hash(re)?;
+                    self.re = Some(re.to_vec());
+                    rest = tail;
+                },
+                Token::S => {
+                    let len = if self.k.is_some() { NOISE_KEY_LEN + TAG_LEN } else { NOISE_KEY_LEN };
+                    if rest.len() < len {
+                        return Err("Handshake message is too short!".to_string());
+                    }
+                    let (encrypted, tail) = rest.split_at(len);
+                    self.rs = Some(self.decrypt_and_hash(encrypted)?);
+                    rest = tail;
+                },
+                dh_token => self.mix_dh(dh_token)?
+            }
+        }
+        self.decrypt_and_hash(rest)
+    }
+
+    /**
+     * Derives the secret key of the connection once the handshake is finished.  
+     * It is the first key of the Noise `Split()`, from which both directions  
+     * derive their keys and IVs like with every other key exchange.
+     */
+    pub fn split(&self) -> Result<Secret, String> {
+        if !self.is_finished() {
+            return Err("Handshake is not finished yet!".to_string());
+        }
+        let mut temp_key = hkdf_extract(self.ck.as_bytes(), &[])?;
+        let secret_key_res = hkdf_expand(temp_key.as_slice(), &[], KEY_LEN);
+        zeroize(temp_key.as_mut_slice());
+        secret_key_res.map(Secret::new)
+    }
+
+    /**
+     * Internal function performing a Diffie-Hellman token and mixing  
+     * the shared secret into the chaining key.
+     */
+    fn mix_dh(&mut self, token: Token) -> Result<(), String> {
+        let (local, remote) = match (token, self.initiator) {
+            (Token::EE, _) => (self.e.as_ref(), self.re.as_ref()),
+            (Token::ES, true) | (Token::SE, false) => (self.e.as_ref(), self.rs.as_ref()),
+            (Token::ES, false) | (Token::SE, true) => (Some(&self.s), self.re.as_ref()),
+            (Token::SS, _) => (Some(&self.s), self.rs.as_ref()),
+            _ => (None, None)
+        };
+        if local.is_none() || remote.is_none() {
+            return Err("Missing key for the handshake!".to_string());
+        }
+        let shared_secret = Secret::new(derive_x25519(local.unwrap(), remote.unwrap().as_slice())?);
+        self.mix_key(shared_secret.as_bytes())
+    }
+
+    /**
+     * Internal function implementing `MixKey()`.
+     */
+    fn mix_key(&mut self, input_key: &[u8]) -> Result<(), String> {
+        let mut temp_key = hkdf_extract(self.ck.as_bytes(), input_key)?;
+        let output_res = hkdf_expand(temp_key.as_slice(), &[], 2 * NOISE_KEY_LEN);
+        zeroize(temp_key.as_mut_slice());
+        let mut output = output_res?;
+        self.ck = Secret::new(output[..NOISE_KEY_LEN].to_vec());
+        self.k = Some(Secret::new(output[NOISE_KEY_LEN..].to_vec()));
+        self.n = 0;
+        zeroize(output.as_mut_slice());
+        Ok(())
+    }
+
+    /**
+     * Internal function implementing `MixHash()`.
+     */
+    fn mix_hash(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut input = self.h.clone();
+        input.extend_from_slice(data);
+        self.h = sha256(input.as_slice())?;
+        Ok(())
+    }
+
+    /**
+     * Internal function implementing `EncryptAndHash()`,  
+     * the plaintext is sent as is until a key is established.
+     */
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let ciphertext = match self.k {
+            Some(ref k) => ChaChaPolyCipher.seal_with_nonce(
+                k.as_bytes(),
+                noise_nonce(self.n).as_slice(),
+                self.h.as_slice(),
+                plaintext
+            )?,
+            None => plaintext.to_vec()
+        };
+        if self.k.is_some() {
+            self.n += 1;
+        }
+        self.mix_hash(ciphertext.as_slice())?;
+        Ok(
+            ciphertext
+        )
+    }
+
+    /**
+     * Internal function implementing `DecryptAndHash()`.
+     */
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        let plaintext = match self.k {
+            Some(ref k) => ChaChaPolyCipher.open_with_nonce(
+                k.as_bytes(),
+                noise_nonce(self.n).as_slice(),
+                self.h.as_slice(),
+                ciphertext
+            )?,
+            None => ciphertext.to_vec()
+        };
+        if self.k.is_some() {
+            self.n += 1;
+        }
+        self.mix_hash(ciphertext)?;
+        Ok(
+            plaintext
+        )
+    }
+}
+
+/**
+ * Builds the ChaChaPoly nonce of the Noise framework,  
+ * 4 zero bytes followed by the little endian counter.
+ */
+fn noise_nonce(n: u64) -> Vec<u8> {
+    let mut nonce = vec![0u8; 4];
+    nonce.extend_from_slice(&n.to_le_bytes());
+    nonce
+}
+
+/**
+ * Computes the SHA-256 hash of data.
+ */
+fn sha256(data: &[u8]) -> Result<Vec<u8>, String> {
+    let digest_res = hash(MessageDigest::sha256(), data);
+    if digest_res.is_err() {
+        return Err("Error computing SHA-256 hash!".to_string());
+    }
+    Ok(
+        digest_res.unwrap().to_vec()
+    )
+}
+
+/**
+ * Builds the prologue of a Noise handshake in `KeyExchange::Noise` mode,  
+ * binding it to the connection and the offered cipher suites, which are  
+ * sent in clear with the `Connect` package.
+ */
+pub fn noise_prologue(connection_id: u32, cipher_suites: &[CipherSuite]) -> Vec<u8> {
+    let mut prologue = b"udps noise".to_vec();
+    prologue.extend_from_slice(&conv_u32_to_bytes(&connection_id));
+    prologue.extend(cipher_suites.iter().map(|suite| *suite as u8));
+    prologue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> PKey<Private> {
+        PKey::private_key_from_raw_bytes(&[byte; NOISE_KEY_LEN], Id::X25519).unwrap()
+    }
+
+    fn hex(string: &str) -> Vec<u8> {
+        (0..string.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn handshakes(pattern: NoisePattern, prologue: &[u8]) -> (NoiseHandshake, NoiseHandshake) {
+        let responder_static = key(3);
+        let responder_public = x25519_public_bytes(&responder_static).unwrap();
+        let initiator = NoiseHandshake::new(pattern, true, key(1), Some(responder_public), prologue).unwrap();
+        let responder = NoiseHandshake::new(pattern, false, responder_static, None, prologue).unwrap();
+        (initiator, responder)
+    }
+
+    /**
+     * Runs the handshake to the end with the given payloads  
+     * and returns the messages sent.
+     */
+    fn run(initiator: &mut NoiseHandshake, responder: &mut NoiseHandshake, payloads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for payload in payloads.iter() {
+            let (writer, reader) = if initiator.is_our_turn() {
+                (&mut *initiator, &mut *responder)
+            } else {
+                (&mut *responder, &mut *initiator)
+            };
+            let message = writer.write_message(payload).unwrap();
+            assert_eq!(reader.read_message(message.as_slice()).unwrap(), payload.to_vec());
+            messages.push(message);
+        }
+        assert!(initiator.is_finished() && responder.is_finished());
+        messages
+    }
+
+    fn check_round_trip(pattern: NoisePattern, payloads: &[&[u8]]) {
+        let (mut initiator, mut responder) = handshakes(pattern, b"prologue");
+        run(&mut initiator, &mut responder, payloads);
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        assert_eq!(initiator.split().unwrap().as_bytes(), responder.split().unwrap().as_bytes());
+        assert_eq!(initiator.remote_static_key().unwrap(), x25519_public_bytes(&key(3)).unwrap().as_slice());
+        assert_eq!(responder.remote_static_key().unwrap(), x25519_public_bytes(&key(1)).unwrap().as_slice());
+    }
+
+    #[test]
+    fn xx_round_trip() {
+        check_round_trip(NoisePattern::XX, &[b"", b"hello", b"done"]);
+    }
+
+    #[test]
+    fn ik_round_trip() {
+        check_round_trip(NoisePattern::IK, &[b"hello", b"done"]);
+    }
+
+    #[test]
+    fn ik_requires_remote_static_key() {
+        assert!(NoiseHandshake::new(NoisePattern::IK, true, key(1), None, &[]).is_err());
+    }
+
+    /**
+     * Test vectors with fixed static keys (1 and 3 repeated) and ephemeral keys  
+     * (2 and 4 repeated), computed with an independent implementation of the spec.
+     */
+    #[test]
+    fn matches_test_vectors() {
+        let (mut initiator, mut responder) = handshakes(NoisePattern::XX, b"udps noise");
+        initiator.e = Some(key(2));
+        responder.e = Some(key(4));
+        let messages = run(&mut initiator, &mut responder, &[b"", b"", b"done"]);
+        assert_eq!(messages[0], hex("ce8d3ad1ccb633ec7b70c17814a5c76ecd029685050d344745ba05870e587d59"));
+        assert_eq!(&messages[2][..8], hex("539a5cf3ae8a0a91").as_slice());
+        assert_eq!(initiator.handshake_hash(), hex("7c6932b966fb95e0f747ad0a7996ce998ce000fcb824ba4073ae988b03452b33").as_slice());
+        assert_eq!(initiator.split().unwrap().as_bytes(), hex("013155fc61857be164b5250358a116758f5d20cf2d867365b23a4e428020d685").as_slice());
+
+        let (mut initiator, mut responder) = handshakes(NoisePattern::IK, b"udps noise");
+        initiator.e = Some(key(2));
+        responder.e = Some(key(4));
+        let messages = run(&mut initiator, &mut responder, &[b"hello", b""]);
+        assert_eq!(messages[1], hex("ac01b2209e86354fb853237b5de0f4fab13c7fcbf433a61c019369617fecf10b275ed0958adb49c5802fccf5d95db645"));
+        assert_eq!(responder.handshake_hash(), hex("bc20c7b725655be727d4d6a51c0fd89f3ec0e081609cc296a7d61eea028ddb29").as_slice());
+        assert_eq!(responder.split().unwrap().as_bytes(), hex("2ceea2107a23f496fca89ca3b1d442c9fb73c9d1b97aad6b1503e5010f48276d").as_slice());
+    }
+
+    #[test]
+    fn rejects_tampered_messages() {
+        let (mut initiator, mut responder) = handshakes(NoisePattern::XX, &[]);
+        let first = initiator.write_message(&[]).unwrap();
+        responder.read_message(first.as_slice()).unwrap();
+        let second = responder.write_message(b"hello").unwrap();
+        // Every byte after the ephemeral key is authenticated
+        for i in NOISE_KEY_LEN..second.len() {
+            let mut tampered = second.clone();
+            tampered[i] ^= 1;
+            assert!(initiator.read_message(tampered.as_slice()).is_err());
+        }
+        assert!(initiator.read_message(&second[..second.len() - 1]).is_err());
+        // A forged message does not spoil the handshake for the genuine one
+        assert_eq!(initiator.read_message(second.as_slice()).unwrap(), b"hello".to_vec());
+        assert!(initiator.read_message(second.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_messages_of_another_prologue() {
+        let (mut initiator, _) = handshakes(NoisePattern::IK, b"prologue");
+        let (_, mut responder) = handshakes(NoisePattern::IK, b"other prologue");
+        let message = initiator.write_message(&[]).unwrap();
+        assert!(responder.read_message(message.as_slice()).is_err());
+    }
+}
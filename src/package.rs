@@ -16,6 +16,9 @@ pub struct Header {
     pub version: [u8; 3],
     pub enc_type: EncType,
     pub crypt_type: CryptType,
+    pub cipher_suite: Option<CipherSuite>,
+    pub key_epoch: u32,
+    pub packet_number: u64,
     pub method_type: MethodType,
     pub connection_id: u32,
     pub package_id: u32,
@@ -42,7 +45,8 @@ pub enum EncType {
 pub enum CryptType {
     None = 0,
     Asymm,
-    Symm
+    Symm,
+    Auth
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
@@ -53,6 +57,25 @@ pub enum MethodType {
     AsymmKey,
     SymmKey,
     Data,
+    Rekey,
+    Cookie,
+    Ticket,
+}
+
+impl Header {
+    /**
+     * Encodes the header as the associated data of a sealed package,  
+     * so none of its fields can be altered without the package being dropped.
+     */
+    pub fn associated_data(&self) -> Result<Vec<u8>, String> {
+        let encode_res = to_vec(self);
+        if encode_res.is_err() {
+            return Err("Unknown error encoding MessagePack header!".to_string());
+        }
+        Ok(
+            encode_res.unwrap()
+        )
+    }
 }
 
 impl Package {
@@ -65,6 +88,9 @@ impl Package {
                 version: crate::VERSION,
                 enc_type: EncType::Raw,
                 crypt_type: CryptType::None,
+                cipher_suite: None,
+                key_epoch: 0,
+                packet_number: 0,
                 method_type: MethodType::Data,
                 connection_id: thread_rng().next_u32(),
                 package_id: thread_rng().next_u32(),
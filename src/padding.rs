@@ -0,0 +1,189 @@
+use std::vec::*;
+
+use rand::prelude::*;
+
+use crate::prelude::*;
+
+/**
+ * Byte separating the payload of a sealed `Data` package from its padding,  
+ * which consists of zero bytes only
+ */
+const PADDING_MARKER: u8 = 0x80;
+
+/**
+ * Bytes of a datagram taken by everything but a padded payload:  
+ * the header, the tag of the sealed payload and header protection
+ */
+pub const PADDING_OVERHEAD: usize = 128;
+
+/**
+ * How the payloads of sealed `Data` packages are padded to hide their length.  
+ * Padding is added inside the sealed payload and removed after opening it,  
+ * so endpoints with different policies can talk to each other.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub enum PaddingPolicy {
+    /**
+     * Payloads are not padded
+     */
+    None,
+    /**
+     * Payloads are padded to the smallest of the given sizes (in bytes) holding them,  
+     * longer payloads to a multiple of the largest size
+     */
+    Buckets(Vec<usize>),
+    /**
+     * Payloads are padded to the given size (in bytes), usually the largest payload  
+     * fitting a datagram of the path MTU. Longer payloads are padded to a multiple of it
+     */
+    Mtu(usize),
+    /**
+     * Up to the given number of random padding bytes are appended to payloads
+     */
+    Random(usize),
+}
+
+impl PaddingPolicy {
+    /**
+     * Returns the length a payload of `len` bytes (including the marker) is padded to.  
+     * Fails if it does not fit into a `usize`.
+     */
+    pub fn padded_len(&self, len: usize) -> Result<usize, Error> {
+        let padded_len = match self {
+            PaddingPolicy::None => Some(len),
+            PaddingPolicy::Buckets(sizes) => {
+                let bucket = sizes.iter().filter(|size| **size >= len).min();
+                match bucket {
+                    Some(size) => Some(*size),
+                    None => Self::round_up(len, sizes.iter().max().cloned().unwrap_or(0))
+                }
+            },
+            PaddingPolicy::Mtu(size) => Self::round_up(len, *size),
+            PaddingPolicy::Random(max) => len.checked_add(thread_rng().gen_range(0, max.saturating_add(1)))
+        };
+        match padded_len {
+            Some(padded_len) => Ok(padded_len),
+            None => Err("Padded payload too large!".to_string())
+        }
+    }
+
+    /**
+     * Appends the marker and the padding to a payload, which is padded  
+     * to no more than `max_len` bytes. Fails if the payload and marker do not fit.
+     */
+    pub fn pad(&self, data: &mut Vec<u8>, max_len: usize) -> Result<(), Error> {
+        if data.len() >= max_len {
+            return Err("Payload too large to pad!".to_string());
+        }
+        data.push(PADDING_MARKER);
+        let padded_len = self.padded_len(data.len()).unwrap_or(max_len);
+        data.resize(padded_len.min(max_len), 0);
+        Ok(())
+    }
+
+    /**
+     * Removes the padding and the marker from a payload.  
+     * Fails if there is no marker, the payload was not padded.
+     */
+    pub fn unpad(data: &mut Vec<u8>) -> Result<(), Error> {
+        let marker_pos = match data.iter().rposition(|byte| *byte != 0) {
+            Some(pos) if data[pos] == PADDING_MARKER => pos,
+            _ => return Err("Padding marker missing!".to_string())
+        };
+        data.truncate(marker_pos);
+        Ok(())
+    }
+
+    fn round_up(len: usize, size: usize) -> Option<usize> {
+        if size == 0 {
+            return Some(len);
+        }
+        len.checked_add(size - 1).map(|len| (len / size) * size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_the_smallest_bucket() {
+        let policy = PaddingPolicy::Buckets(vec![256, 64, 1024]);
+        assert_eq!(policy.padded_len(1), Ok(64));
+        assert_eq!(policy.padded_len(64), Ok(64));
+        assert_eq!(policy.padded_len(65), Ok(256));
+        assert_eq!(policy.padded_len(1025), Ok(2048));
+        assert_eq!(PaddingPolicy::Buckets(Vec::new()).padded_len(10), Ok(10));
+        assert!(policy.padded_len(usize::max_value() - 10).is_err());
+    }
+
+    #[test]
+    fn pads_to_multiples_of_the_mtu() {
+        let policy = PaddingPolicy::Mtu(1200);
+        assert_eq!(policy.padded_len(1), Ok(1200));
+        assert_eq!(policy.padded_len(1200), Ok(1200));
+        assert_eq!(policy.padded_len(1201), Ok(2400));
+        assert_eq!(PaddingPolicy::Mtu(0).padded_len(10), Ok(10));
+        assert!(policy.padded_len(usize::max_value() - 10).is_err());
+    }
+
+    #[test]
+    fn random_padding_stays_in_range() {
+        let policy = PaddingPolicy::Random(16);
+        for _ in 0..100 {
+            let padded_len = policy.padded_len(10).unwrap();
+            assert!(padded_len >= 10);
+            assert!(padded_len <= 26);
+        }
+        assert!(PaddingPolicy::Random(usize::max_value()).padded_len(0).is_ok());
+        // Only fails if the padding drawn does not fit
+        for _ in 0..100 {
+            if let Ok(padded_len) = PaddingPolicy::Random(usize::max_value()).padded_len(1000) {
+                assert!(padded_len >= 1000);
+            }
+        }
+    }
+
+    #[test]
+    fn clamps_padding_to_the_maximum_length() {
+        let mut data = vec![1; 10];
+        PaddingPolicy::Random(usize::max_value()).pad(&mut data, 100).unwrap();
+        assert!(data.len() <= 100);
+        PaddingPolicy::unpad(&mut data).unwrap();
+        assert_eq!(data, vec![1; 10]);
+        let mut data = vec![1; 10];
+        PaddingPolicy::Mtu(1200).pad(&mut data, 100).unwrap();
+        assert_eq!(data.len(), 100);
+        let mut data = vec![1; 100];
+        assert!(PaddingPolicy::None.pad(&mut data, 100).is_err());
+        assert_eq!(data, vec![1; 100]);
+    }
+
+    #[test]
+    fn unpads_padded_payloads() {
+        let policies = vec![
+            PaddingPolicy::None,
+            PaddingPolicy::Buckets(vec![32, 128]),
+            PaddingPolicy::Mtu(100),
+            PaddingPolicy::Random(8)
+        ];
+        for policy in policies {
+            // Trailing zero bytes of the payload itself must survive
+            let payload = vec![1, 2, 0x80, 0, 0];
+            let mut data = payload.clone();
+            policy.pad(&mut data, 1024).unwrap();
+            PaddingPolicy::unpad(&mut data).unwrap();
+            assert_eq!(data, payload);
+        }
+    }
+
+    #[test]
+    fn rejects_payloads_without_marker() {
+        assert!(PaddingPolicy::unpad(&mut Vec::new()).is_err());
+        assert!(PaddingPolicy::unpad(&mut vec![0, 0, 0]).is_err());
+        assert!(PaddingPolicy::unpad(&mut vec![1, 2, 3, 0, 0]).is_err());
+        let mut data = vec![0x80];
+        PaddingPolicy::unpad(&mut data).unwrap();
+        assert!(data.is_empty());
+    }
+}
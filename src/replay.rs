@@ -0,0 +1,144 @@
+/**
+ * Number of packet numbers below the highest one received  
+ * that are still accepted if they arrive out of order
+ */
+pub const REPLAY_WINDOW_SIZE: u64 = 1024;
+
+const WINDOW_WORDS: usize = (REPLAY_WINDOW_SIZE / 64) as usize;
+
+/**
+ * Anti-replay sliding window over authenticated packet numbers.  
+ * A packet number is accepted once, and only if it is either higher than  
+ * all received so far or at most `REPLAY_WINDOW_SIZE` below the highest one.
+ */
+pub struct ReplayWindow {
+    /**
+     * Highest packet number accepted so far
+     */
+    pub highest: Option<u64>,
+    /**
+     * Bitmap of accepted packet numbers, indexed modulo the window size
+     */
+    bitmap: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    /**
+     * Creates a new, empty window.
+     */
+    pub fn new() -> Self {
+        Self {
+            highest: None,
+            bitmap: [0; WINDOW_WORDS]
+        }
+    }
+
+    /**
+     * Accepts a packet number, returning false if it is a  
+     * duplicate or too old. Only call this for authenticated packages.
+     */
+    pub fn accept(&mut self, packet_number: u64) -> bool {
+        match self.highest {
+            Some(highest) if packet_number <= highest => {
+                if highest - packet_number >= REPLAY_WINDOW_SIZE || self.is_set(packet_number) {
+                    return false;
+                }
+            },
+            Some(highest) => {
+                // Slide the window, forgetting the packet numbers that fall out of it
+                if packet_number - highest >= REPLAY_WINDOW_SIZE {
+                    self.bitmap = [0; WINDOW_WORDS];
+                } else {
+                    for cleared in (highest + 1)..=packet_number {
+                        self.clear(cleared);
+                    }
+                }
+                self.highest = Some(packet_number);
+            },
+            None => {
+                self.highest = Some(packet_number);
+            }
+        };
+        self.set(packet_number);
+        true
+    }
+
+    fn is_set(&self, packet_number: u64) -> bool {
+        let bit = packet_number % REPLAY_WINDOW_SIZE;
+        self.bitmap[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, packet_number: u64) {
+        let bit = packet_number % REPLAY_WINDOW_SIZE;
+        self.bitmap[(bit / 64) as usize] |= 1 << (bit % 64);
+    }
+
+    fn clear(&mut self, packet_number: u64) {
+        let bit = packet_number % REPLAY_WINDOW_SIZE;
+        self.bitmap[(bit / 64) as usize] &= !(1 << (bit % 64));
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(0));
+        assert!(!window.accept(0));
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+    }
+
+    #[test]
+    fn accepts_reordered_packets_once() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(10));
+        assert!(window.accept(7));
+        assert!(window.accept(9));
+        assert!(!window.accept(7));
+        assert!(window.accept(11));
+        assert!(!window.accept(9));
+    }
+
+    #[test]
+    fn rejects_old_packets() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(REPLAY_WINDOW_SIZE + 10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+        assert!(!window.accept(0));
+    }
+
+    #[test]
+    fn wraps_around_the_bitmap() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(0));
+        assert!(window.accept(1));
+        // Same bitmap slot as 1, the slot must have been cleared when sliding
+        assert!(window.accept(REPLAY_WINDOW_SIZE + 1));
+        assert!(!window.accept(REPLAY_WINDOW_SIZE + 1));
+        assert!(!window.accept(1));
+        assert!(window.accept(REPLAY_WINDOW_SIZE));
+        assert!(window.accept(2));
+        assert!(!window.accept(2));
+    }
+
+    #[test]
+    fn jumps_far_ahead() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(3));
+        assert!(window.accept(u64::max_value()));
+        assert!(!window.accept(u64::max_value()));
+        assert!(window.accept(u64::max_value() - 3));
+        assert!(!window.accept(3));
+    }
+}
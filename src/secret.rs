@@ -0,0 +1,100 @@
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{
+    compiler_fence,
+    Ordering
+};
+
+use crate::prelude::*;
+
+/**
+ * Overwrites bytes with zeros, in a way the compiler can not optimize away.
+ */
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe {
+            ptr::write_volatile(byte, 0);
+        }
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/**
+ * Key material which is wiped from memory when dropped.  
+ * It can not be cloned, copies have to be made explicitly with `duplicate`  
+ * and are wiped as well. The `Debug` output never contains the bytes.
+ */
+pub struct Secret {
+    bytes: Vec<u8>,
+}
+
+impl Secret {
+    /**
+     * Takes ownership of key material. Copies the vector  
+     * left behind while it grew can not be wiped.
+     */
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes: bytes
+        }
+    }
+
+    /**
+     * Creates a new secret of random bytes.
+     */
+    pub fn random(len: usize) -> Self {
+        Self::new(generate_random_bytes(len))
+    }
+
+    /**
+     * Gets the key material.
+     */
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    /**
+     * Gets the length of the key material in bytes.
+     */
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /**
+     * Returns true if there is no key material.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /**
+     * Creates a copy, which is wiped on drop as well.
+     */
+    pub fn duplicate(&self) -> Self {
+        Self::new(self.bytes.clone())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Also wipe whatever is left behind the length, e.g. after truncating
+        let capacity = self.bytes.capacity();
+        self.bytes.resize(capacity, 0);
+        zeroize(self.bytes.as_mut_slice());
+    }
+}
+
+/**
+ * Comparing in constant time, so timing does not reveal the key material
+ */
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.as_bytes(), other.as_bytes())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret([REDACTED; {} bytes])", self.bytes.len())
+    }
+}
@@ -0,0 +1,393 @@
+use std::convert::*;
+use std::sync::*;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::vec::*;
+
+use rmps::*;
+use openssl::x509::X509;
+
+use crate::prelude::*;
+use crate::connect_token::unix_time;
+
+/**
+ * Associated data binding sealed tickets to their purpose
+ */
+const TICKET_AAD: &[u8] = b"udps resumption ticket";
+
+/**
+ * Label of the keying material both endpoints derive the resumption secret from
+ */
+const RESUMPTION_LABEL: &str = "udps resumption";
+
+/**
+ * Contents of a resumption ticket. The accepting endpoint seals it with its  
+ * ticket key once a connection is secure and sends it to the initiating endpoint,  
+ * which can not read or alter it. Sending it back with a new `Connect` package  
+ * lets the new connection be keyed from the resumption secret in one round trip.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ResumptionTicket {
+    /**
+     * Secret derived from the connection the ticket was issued for
+     */
+    pub resumption_secret: Vec<u8>,
+    /**
+     * Cipher suite negotiated for the connection
+     */
+    pub cipher_suite: CipherSuite,
+    /**
+     * Public key of the initiating endpoint (binary DER), if known
+     */
+    pub public_key: Option<Vec<u8>>,
+    /**
+     * Leaf certificate of the initiating endpoint (binary DER), if any,  
+     * only verified if `trusted_ca` was configured
+     */
+    pub certificate: Option<Vec<u8>>,
+    /**
+     * Expiry as seconds since the UNIX epoch
+     */
+    pub expires_at: u64,
+}
+
+/**
+ * Wiping the resumption secret once the ticket is sealed or used
+ */
+impl Drop for ResumptionTicket {
+    fn drop(&mut self) {
+        zeroize(self.resumption_secret.as_mut_slice());
+    }
+}
+
+impl ResumptionTicket {
+    /**
+     * Encodes and seals the ticket with the ticket key (AES-256-GCM).
+     */
+    pub fn seal(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let encode_res = to_vec(self);
+        if encode_res.is_err() {
+            return Err("Unknown error encoding MessagePack resumption ticket!".to_string());
+        }
+        let mut encoded = encode_res.unwrap();
+        let sealed_res = CipherSuite::Aes256Gcm.cipher().seal(key, TICKET_AAD, encoded.as_slice());
+        zeroize(encoded.as_mut_slice());
+        sealed_res
+    }
+
+    /**
+     * Opens and decodes a sealed ticket with the ticket key.  
+     * Fails if the ticket was not sealed with this key or altered.
+     */
+    pub fn open(key: &[u8], sealed: &[u8]) -> Result<Self, Error> {
+        let mut data = CipherSuite::Aes256Gcm.cipher().open(key, TICKET_AAD, sealed)?;
+        let ticket_res = from_slice::<Self>(data.as_slice());
+        zeroize(data.as_mut_slice());
+        if ticket_res.is_err() {
+            return Err("Unknown error decoding MessagePack resumption ticket!".to_string());
+        }
+        Ok(
+            ticket_res.unwrap()
+        )
+    }
+
+    /**
+     * Returns true if the ticket expired.
+     */
+    pub fn is_expired(&self) -> bool {
+        unix_time() >= self.expires_at
+    }
+}
+
+/**
+ * Resumption ticket received from an endpoint we connected to,  
+ * together with what we need to resume a connection with it.
+ */
+pub struct SessionTicket {
+    /**
+     * Sealed ticket, sent back with the `Connect` package
+     */
+    pub ticket: Vec<u8>,
+    /**
+     * Secret derived from the connection the ticket was issued for
+     */
+    pub resumption_secret: Secret,
+    /**
+     * Cipher suite negotiated for the connection
+     */
+    pub cipher_suite: CipherSuite,
+    /**
+     * Public key of the accepting endpoint (binary DER), if known
+     */
+    pub public_key: Option<Vec<u8>>,
+    /**
+     * Verified certificate of the accepting endpoint (binary DER), if any
+     */
+    pub certificate: Option<Vec<u8>>,
+    /**
+     * Time the ticket expires
+     */
+    pub expires: Instant,
+}
+
+impl SessionTicket {
+    /**
+     * Returns true if the ticket expired.
+     */
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+}
+
+/**
+ * Stores the public key and certificate (binary DER) kept  
+ * in a ticket as those of the remote endpoint of a connection.
+ */
+fn restore_identity(conn: &ConnectionArc, public_key: &Option<Vec<u8>>, certificate: &Option<Vec<u8>>) -> Result<(), Error> {
+    if let Some(ref public_key) = *public_key {
+        conn.set_public_key(public_key.clone())?;
+    }
+    if let Some(ref certificate) = *certificate {
+        let certificate_res = X509::from_der(certificate.as_slice());
+        if certificate_res.is_err() {
+            return Err("Error decoding DER to certificate!".to_string());
+        }
+        *conn.certificate.write().unwrap() = Some(certificate_res.unwrap());
+    }
+    Ok(())
+}
+
+impl Endpoint {
+    /**
+     * Internal function returning the resumption ticket to send with  
+     * a new connection to an address, if we have a usable one.  
+     * Only the multi round trip key exchanges are resumed.
+     */
+    pub(crate) fn resumption_ticket(&self, addr: &String) -> Option<Arc<SessionTicket>> {
+        if self.config.transport != Transport::Udps ||
+            (self.config.key_exchange != KeyExchange::Rsa && self.config.key_exchange != KeyExchange::Ecdh) {
+            return None;
+        }
+        let mut tickets = self.tickets.write().unwrap();
+        let usable = match tickets.get(addr) {
+            Some(ticket) => !ticket.is_expired() && self.config.cipher_suites.contains(&ticket.cipher_suite),
+            None => false
+        };
+        if !usable {
+            tickets.remove(addr);
+            return None;
+        }
+        tickets.get(addr).cloned()
+    }
+
+    /**
+     * Internal function issuing a resumption ticket with the `Ticket` method  
+     * once a connection initiated by the remote endpoint is secure,  
+     * if `ticket_lifetime` is configured.
+     */
+    pub(crate) fn check_ticket(&self, conn: &ConnectionArc) {
+        if self.config.ticket_lifetime.is_none() ||
+            self.config.transport != Transport::Udps ||
+            (self.config.key_exchange != KeyExchange::Rsa && self.config.key_exchange != KeyExchange::Ecdh) ||
+            conn.initiator ||
+            *conn.crypt_state.read().unwrap() != CryptState::Symm ||
+            conn.ticket_issued.load(Ordering::Relaxed) {
+            return;
+        }
+        let lifetime = self.config.ticket_lifetime.unwrap();
+        let resumption_secret_res = conn.export_keying_material(RESUMPTION_LABEL, &[], KEY_LEN);
+        if resumption_secret_res.is_err() {
+            return;
+        }
+        let ticket = ResumptionTicket {
+            resumption_secret: resumption_secret_res.unwrap(),
+            cipher_suite: conn.get_cipher_suite().unwrap(),
+            public_key: conn.get_public_key().and_then(|public_key| public_key.public_key_to_der().ok()),
+            certificate: conn.certificate.read().unwrap().as_ref().and_then(|certificate| certificate.to_der().ok()),
+            expires_at: unix_time() + lifetime / 1000
+        };
+        let sealed_res = ticket.seal(self.ticket_key.as_bytes());
+        if sealed_res.is_err() {
+            return;
+        }
+        let message = TicketMessage {
+            ticket: sealed_res.unwrap(),
+            lifetime: lifetime
+        };
+        let mut package = Package::new_default();
+        package.header.connection_id = conn.id;
+        package.header.method_type = MethodType::Ticket;
+        package.header.ack = true;
+        let data_res = message.try_into();
+        if data_res.is_err() {
+            return;
+        }
+        package.data = data_res.unwrap();
+        conn.ticket_issued.store(true, Ordering::Relaxed);
+        self.send(package).unwrap_or(0);
+    }
+
+    /**
+     * Internal function for handling incoming packages  
+     * with the `Ticket` method.  
+     * The ticket replaces any previous one for the address of the connection.
+     */
+    pub(crate) fn handle_ticket(&self, conn: ConnectionArc, package: Package) {
+        // Only accept tickets sealed with a secret key we already trust
+        if !conn.initiator || package.header.crypt_type != CryptType::Symm {
+            return;
+        }
+        let message_res = TicketMessage::try_from(package.data);
+        let cipher_suite = conn.get_cipher_suite();
+        if message_res.is_err() || cipher_suite.is_none() {
+            return;
+        }
+        let message = message_res.unwrap();
+        let resumption_secret_res = conn.export_keying_material(RESUMPTION_LABEL, &[], KEY_LEN);
+        if resumption_secret_res.is_err() {
+            return;
+        }
+        let ticket = SessionTicket {
+            ticket: message.ticket,
+            resumption_secret: Secret::new(resumption_secret_res.unwrap()),
+            cipher_suite: cipher_suite.unwrap(),
+            public_key: conn.get_public_key().and_then(|public_key| public_key.public_key_to_der().ok()),
+            certificate: conn.certificate.read().unwrap().as_ref().and_then(|certificate| certificate.to_der().ok()),
+            expires: Instant::now() + Duration::from_millis(message.lifetime)
+        };
+        self.tickets.write().unwrap().insert(conn.address.clone(), Arc::new(ticket));
+    }
+
+    /**
+     * Internal function answering a `Connect` package in `KeyExchange::Rsa` or  
+     * `KeyExchange::Ecdh` mode if it carries a resumption ticket. If we can open  
+     * the unexpired ticket, the secret key is derived from it right away and our  
+     * nonce sent back with the `SymmKey` method, otherwise we tell the remote  
+     * endpoint to continue with the full handshake.
+     */
+    pub(crate) fn handle_resumption(&self, conn: ConnectionArc, package: Package) {
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        // Retransmitted `Connect` packages are already acknowledged, nothing else to do
+        if conn.initiator || *crypt_state != CryptState::None {
+            return;
+        }
+        let message_res = ConnectMessage::try_from(package.data);
+        if message_res.is_err() {
+            return;
+        }
+        let message = message_res.unwrap();
+        if message.ticket.is_none() {
+            return;
+        }
+        let initiator_nonce = message.nonce.unwrap_or(Vec::new());
+        let ticket = ResumptionTicket::open(self.ticket_key.as_bytes(), message.ticket.unwrap().as_slice())
+            .ok()
+            .filter(|ticket| {
+                !ticket.is_expired() &&
+                initiator_nonce.len() == KEY_LEN &&
+                self.config.cipher_suites.contains(&ticket.cipher_suite)
+            });
+        let early_data_accepted = conn.early_data_accepted.load(Ordering::Relaxed);
+        let mut answer = ResumeMessage {
+            accepted: false,
+            early_data_accepted: early_data_accepted,
+            nonce: Vec::new(),
+            mac: Vec::new()
+        };
+        if let Some(ticket) = ticket {
+            let nonce = generate_random_bytes(KEY_LEN);
+            let resumption_secret = Secret::new(ticket.resumption_secret.clone());
+            let secret_res = ResumeMessage::derive_secret(&resumption_secret, &initiator_nonce, &nonce)
+                .and_then(|secret_key| {
+                    let mac = ResumeMessage::compute_mac(&secret_key, &initiator_nonce, &nonce, early_data_accepted)?;
+                    restore_identity(&conn, &ticket.public_key, &ticket.certificate)?;
+                    Ok(
+                        (secret_key, mac)
+                    )
+                });
+            if let Ok((secret_key, mac)) = secret_res {
+                conn.set_secret(secret_key);
+                *conn.cipher_suite.write().unwrap() = Some(ticket.cipher_suite);
+                conn.resumed.store(true, Ordering::Relaxed);
+                *crypt_state = CryptState::Symm;
+                answer = ResumeMessage {
+                    accepted: true,
+                    early_data_accepted: early_data_accepted,
+                    nonce: nonce,
+                    mac: mac
+                };
+            }
+        }
+        drop(crypt_state);
+
+        let mut response_package = Package::new_default();
+        response_package.header.connection_id = conn.id;
+        response_package.header.method_type = MethodType::SymmKey;
+        response_package.header.ack = true;
+        let data_res = answer.try_into();
+        if data_res.is_err() {
+            return;
+        }
+        response_package.data = data_res.unwrap();
+        self.send(response_package).unwrap_or(0);
+    }
+
+    /**
+     * Internal function for handling the `SymmKey` package answering  
+     * our `Connect` package with a resumption ticket.  
+     * If the ticket was accepted, the secret key is derived and the MAC of  
+     * the accepting endpoint checked, after which the connection is secure.  
+     * Otherwise the ticket is forgotten and the full handshake started.
+     */
+    pub(crate) fn handle_resume_answer(&self, conn: ConnectionArc, package: Package) {
+        let mut crypt_state = conn.crypt_state.write().unwrap();
+        if *crypt_state != CryptState::None {
+            return;
+        }
+        let message_res = ResumeMessage::try_from(package.data);
+        let nonce_opt = conn.handshake_nonce.read().unwrap().clone();
+        let ticket_opt = conn.ticket.read().unwrap().clone();
+        if message_res.is_err() || nonce_opt.is_none() || ticket_opt.is_none() {
+            return;
+        }
+        let message = message_res.unwrap();
+        let own_nonce = nonce_opt.unwrap();
+        let ticket = ticket_opt.unwrap();
+        if !message.accepted {
+            // Anybody on the path could send this, so it only falls back to the
+            // handshake for this connection and the stored ticket is kept until it expires
+            *conn.ticket.write().unwrap() = None;
+            *conn.handshake_nonce.write().unwrap() = None;
+            drop(crypt_state);
+            // If our `Connect` package is not acknowledged yet, that starts the handshake
+            let connect_pending = self.ack_list.read().unwrap().values().any(|package_ack| {
+                package_ack.cached_package.header.method_type == MethodType::Connect &&
+                package_ack.cached_package.header.connection_id == conn.id
+            });
+            if !connect_pending {
+                self.send_public_key(&conn, self.config.cipher_suites.clone(), None).unwrap_or(0);
+            }
+            return;
+        }
+        let secret_res = ResumeMessage::derive_secret(&ticket.resumption_secret, &own_nonce, &message.nonce);
+        if secret_res.is_err() {
+            return;
+        }
+        let secret_key = secret_res.unwrap();
+        let mac_res = ResumeMessage::compute_mac(&secret_key, &own_nonce, &message.nonce, message.early_data_accepted);
+        if mac_res.is_err() || !constant_time_eq(mac_res.unwrap().as_slice(), message.mac.as_slice()) {
+            // Forged answer
+            return;
+        }
+        if restore_identity(&conn, &ticket.public_key, &ticket.certificate).is_err() {
+            return;
+        }
+        conn.set_secret(secret_key);
+        *conn.cipher_suite.write().unwrap() = Some(ticket.cipher_suite);
+        *conn.handshake_nonce.write().unwrap() = None;
+        *conn.ticket.write().unwrap() = None;
+        conn.resumed.store(true, Ordering::Relaxed);
+        conn.early_data_accepted.store(message.early_data_accepted, Ordering::Relaxed);
+        *crypt_state = CryptState::Symm;
+    }
+}